@@ -1,51 +1,76 @@
 import * as React from "react";
 import { GameCanvas } from "./components/game_canvas";
-import { SimulationServiceClient } from "./grpc-client/updates.client";
+import { RoomServiceClient, SimulationServiceClient } from "./grpc-client/updates.client";
 import { GrpcWebFetchTransport } from "@protobuf-ts/grpcweb-transport";
-import { Coordinates, Orientation } from "./grpc-client/updates";
+import { ColliderDescription, SpatialData, TransformEncoding } from "./grpc-client/updates";
 
 function App(): React.ReactElement {
-    const [coor, setCoor] = React.useState<Coordinates>({
-        x: 0,
-        y: 0,
-        z: 0,
-    });
-    const [orient, setOrient] = React.useState<Orientation>({
-        i: 0,
-        j: 0,
-        k: 0,
-        w: 0,
-    });
+    const [colliders, setColliders] = React.useState<ColliderDescription[]>([]);
+    const [bodies, setBodies] = React.useState<Record<string, SpatialData>>({});
 
-    const simulationService = React.useMemo(
+    const [roomService, simulationService] = React.useMemo(
         () => {
             const transport = new GrpcWebFetchTransport({
                 format: "text",
                 baseUrl: "http://0.0.0.0:6969",
             });
-            return new SimulationServiceClient(transport);
+            return [new RoomServiceClient(transport), new SimulationServiceClient(transport)];
         }, []);
     
     React.useEffect(() => {
         const subscribe = async () => {
-            for await (const resp of simulationService.subscribeToSimulation({ ok: true }).responses) {
-                if (resp.spatialUpdates.length === 0) {
-                    continue;
-                }
+            // Subscribing without a player session would watch as a delayed spectator.
+            const { response: joined } = await roomService.join({
+                roomId: "",
+                sessionToken: "",
+                spectator: false,
+            });
+            const meta = { authorization: `Bearer ${joined.sessionToken}` };
+
+            for await (const { event } of simulationService.subscribeToSimulation({
+                encoding: TransformEncoding.FullPrecision,
+            }, { meta }).responses) {
+                switch (event.oneofKind) {
+                    case "levelChanged":
+                        setColliders(event.levelChanged.colliders);
+                        setBodies({});
+                        break;
+                    case "snapshot": {
+                        const snapshot = event.snapshot;
+                        if (snapshot.spatialUpdates.length === 0) {
+                            break;
+                        }
 
-                const spatial = resp.spatialUpdates[0];
-                if(spatial.coordinates && spatial.orientation) {
-                    setCoor(spatial.coordinates);
-                    setOrient(spatial.orientation);
+                        // Deltas only carry bodies that moved, keyframes carry all of them.
+                        setBodies(prev => {
+                            const next = snapshot.keyframe ? {} : { ...prev };
+                            for (const spatial of snapshot.spatialUpdates) {
+                                next[spatial.id.toString()] = spatial;
+                            }
+                            return next;
+                        });
+                        break;
+                    }
+                    case "entityDespawned": {
+                        const id = event.entityDespawned.id.toString();
+                        setBodies(prev => {
+                            const next = { ...prev };
+                            delete next[id];
+                            return next;
+                        });
+                        break;
+                    }
+                    default:
+                        break;
                 }
             }
         };
         subscribe();
-    }, [simulationService]);
+    }, [roomService, simulationService]);
 
     return (
         <div id="app-main">
-            <GameCanvas coor={coor} orient={orient}/>
+            <GameCanvas colliders={colliders} bodies={bodies}/>
         </div>
     );
 }
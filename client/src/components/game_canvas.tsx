@@ -1,12 +1,18 @@
 import React from "react";
-import { Canvas, useFrame } from "@react-three/fiber";
+import { Canvas } from "@react-three/fiber";
 import { OrbitControls } from "@react-three/drei";
-import { Coordinates, Orientation } from "../grpc-client/updates";
+import {
+    ColliderDescription,
+    Coordinates,
+    Orientation,
+    ShapeType,
+    SpatialData,
+} from "../grpc-client/updates";
 import { Euler, Quaternion, Vector3 } from "three";
 
 export function GameCanvas(props: {
-    coor: Coordinates,
-    orient: Orientation,
+    colliders: ColliderDescription[],
+    bodies: Record<string, SpatialData>,
 }): React.ReactElement {
        
     return (
@@ -18,47 +24,70 @@ export function GameCanvas(props: {
         >
             <OrbitControls />
             <ambientLight/>
-            <TestFloor/>
-            <Test {...props}/>
+            {props.colliders.map((collider, i) => (
+                <Collider
+                    key={i}
+                    collider={collider}
+                    body={collider.parent !== undefined
+                        ? props.bodies[collider.parent.toString()]
+                        : undefined}
+                />
+            ))}
         </Canvas>
     );
 }
 
+function toPosition(coor?: Coordinates): Vector3 {
+    return new Vector3(coor?.x ?? 0, coor?.y ?? 0, coor?.z ?? 0);
+}
 
-function Test(props: {
-    coor: Coordinates,
-    orient: Orientation,
-}): React.ReactElement {
-    //useFrame(state => {
-    //    state.camera.lookAt( new Vector3(...Object.values(props.coor)));
-    //    state.camera.position.set(props.coor.x, props.coor.y -2, props.coor.z)
-    //});
-    const position = new Vector3(...Object.values(props.coor));
-    const quaternion = new Quaternion(...Object.values(props.orient));
-    const euler = new Euler().setFromQuaternion(quaternion);
-    const scale = new Vector3(10, 10, 10);
-    console.log(props.coor);
-    return (
-        <mesh 
-            position={position} 
-            rotation={euler}
-            scale={scale}
-        >
-            <meshStandardMaterial color={"blue"}/>
-            <boxGeometry/>
-        </mesh>
-    );
+function toRotation(orient?: Orientation): Euler {
+    const quaternion = new Quaternion(orient?.i ?? 0, orient?.j ?? 0, orient?.k ?? 0, orient?.w ?? 1);
+    return new Euler().setFromQuaternion(quaternion);
 }
 
-function TestFloor(): React.ReactElement {
+function Collider(props: {
+    collider: ColliderDescription,
+    body?: SpatialData,
+}): React.ReactElement | null {
+    const { collider, body } = props;
+
+    // Colliders on moving bodies wait for their first pose.
+    if (collider.parent !== undefined && !body) {
+        return null;
+    }
+
     return (
-        <mesh 
-            position={new Vector3(0, 0, 0)}
-            scale={new Vector3(100, 0, 100)} 
+        <group
+            position={toPosition(body?.coordinates)}
+            rotation={toRotation(body?.orientation)}
         >
-            <meshStandardMaterial color={"green"}/>
-            <boxGeometry/>
-        </mesh>
+            <mesh
+                position={toPosition(collider.coordinates)}
+                rotation={toRotation(collider.orientation)}
+            >
+                <meshStandardMaterial color={collider.hints?.color || "grey"}/>
+                <ColliderGeometry collider={collider}/>
+            </mesh>
+        </group>
     );
 }
 
+function ColliderGeometry(props: {
+    collider: ColliderDescription,
+}): React.ReactElement | null {
+    const { x, y, z } = props.collider.halfExtents ?? { x: 0, y: 0, z: 0 };
+
+    switch (props.collider.shape) {
+        case ShapeType.Cuboid:
+            return <boxGeometry args={[2 * x, 2 * y, 2 * z]}/>;
+        case ShapeType.Ball:
+            return <sphereGeometry args={[x]}/>;
+        case ShapeType.Capsule:
+            return <capsuleGeometry args={[x, 2 * y]}/>;
+        case ShapeType.Cylinder:
+            return <cylinderGeometry args={[x, x, 2 * y]}/>;
+        default:
+            return null;
+    }
+}
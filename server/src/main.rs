@@ -1,62 +1,109 @@
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
     time::Duration,
 };
 
-use anyhow::Result;
-use service::simulation_service;
-use simulation::{Simulation, SimulationContext};
-use tokio::sync::{broadcast, mpsc};
+use anyhow::{Context, Result};
+use room::{RoomConfig, RoomRegistry};
+use service::{chat_service, room_service, simulation_service};
+use session::{SessionInterceptor, SessionStore};
+use simulation::aggregation::AggregationPolicy;
 use tonic::transport::Server;
-use tower_http::cors;
 use tracing::{error, info};
 
 mod chat;
+mod matchmaking;
+mod room;
 mod service;
+mod session;
 mod simulation;
+mod ws;
 
 pub mod updates {
     tonic::include_proto!("updates");
+
+    impl From<simulation_event::Event> for SimulationEvent {
+        fn from(event: simulation_event::Event) -> Self {
+            Self { event: Some(event) }
+        }
+    }
 }
 
 use updates::{
-    simulation_service_server::SimulationServiceServer, InstructionUpdate, SimulationUpdate,
+    chat_service_server::ChatServiceServer, room_service_server::RoomServiceServer,
+    simulation_service_server::SimulationServiceServer,
 };
 
 #[tokio::main]
-#[allow(unused)]
 async fn main() -> Result<()> {
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 6969);
+    let ws_port = std::env::var("WS_PORT")
+        .map(|p| p.parse::<u16>())
+        .unwrap_or(Ok(6970))?;
+    let ws_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), ws_port);
 
     let _ = tracing_subscriber::fmt().pretty().init();
 
-    let (sim_tx, mut sim_rx) = broadcast::channel::<SimulationUpdate>(10);
-    let (ins_tx, mut ins_rx) = mpsc::channel::<InstructionUpdate>(1000);
-    let update_interval = Duration::from_millis(8);
-    let instruction_interval = Duration::from_millis(200);
+    let pause_when_empty = std::env::var("PAUSE_WHEN_EMPTY").is_ok_and(|v| v == "true");
+    let aggregation_policy = std::env::var("AGGREGATION_POLICY")
+        .map(|p| p.parse::<AggregationPolicy>())
+        .unwrap_or(Ok(AggregationPolicy::default()))?;
+
+    let spectator_delay = std::env::var("SPECTATOR_DELAY_MS")
+        .map(|d| d.parse::<u64>())
+        .unwrap_or(Ok(0))?;
 
-    let mut sim = Simulation::new(
-        sim_tx.clone(),
-        update_interval,
-        ins_rx,
-        instruction_interval,
+    let rooms = Arc::new(RoomRegistry::new(RoomConfig {
+        physics_timestep: Duration::from_secs_f64(1. / 120.),
+        snapshot_interval: Duration::from_millis(33),
+        instruction_interval: Duration::from_millis(200),
+        player_idle_timeout: Duration::from_secs(30),
+        session_grace_period: Duration::from_secs(20),
+        spectator_delay: Duration::from_millis(spectator_delay),
+        aggregation_policy,
+        split_control: std::env::var("CONTROL_MODE").is_ok_and(|m| m == "split"),
+        pause_when_empty,
+        room_idle_timeout: Duration::from_secs(60),
+        max_rooms: 64,
+        spectator_session_ttl: Duration::from_secs(60 * 60),
+    })?);
+    let sessions = Arc::new(SessionStore::default());
+    tokio::spawn(
+        rooms
+            .clone()
+            .clean_up_every(sessions.clone(), Duration::from_secs(10)),
     );
-    let simulation_thread = tokio::spawn(async move {
-        info!("Starting simulation thread");
-        sim.run(&mut SimulationContext::default()).await
-    });
 
-    let sim_up_svc = simulation_service::SimulationUpdateService::new(sim_rx, ins_tx);
-    let sim_up_server = SimulationServiceServer::new(sim_up_svc);
+    let sim_up_svc = simulation_service::SimulationUpdateService::new(rooms.clone());
+    let interceptor = SessionInterceptor::new(sessions.clone());
 
-    let cors = cors::CorsLayer::new().allow_origin(cors::Any);
+    let ws_server = ws::run_server(sim_up_svc.clone(), sessions.clone(), ws_addr);
+    let sim_up_server = SimulationServiceServer::with_interceptor(sim_up_svc, interceptor.clone());
+    let chat_server = ChatServiceServer::with_interceptor(
+        chat_service::ChatUpdateService::new(rooms.clone()),
+        interceptor.clone(),
+    );
+    let room_server = RoomServiceServer::with_interceptor(
+        room_service::RoomUpdateService::new(rooms, sessions),
+        interceptor,
+    );
 
     let server = Server::builder()
         .accept_http1(true)
         .add_service(tonic_web::enable(sim_up_server))
+        .add_service(tonic_web::enable(chat_server))
+        .add_service(tonic_web::enable(room_server))
         .serve(addr);
 
     info!("Starting server at {addr}");
-    tokio::join!(simulation_thread, server);
+    // Either transport failing, like its port being taken, stops the server
+    // rather than leaving it half up.
+    tokio::try_join!(
+        async { server.await.context("gRPC server failed") },
+        async { ws_server.await.context("WebSocket server failed") },
+    )
+    .inspect_err(|e| error!(err = %format!("{e:#}"), "Server stopped"))?;
+
     Ok(())
 }
@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::Stream;
+use tonic::Status;
+use tracing::{info, warn};
+
+use crate::{
+    room::RoomRegistry,
+    session::SessionStore,
+    updates::{queue_update::Update, QueueStatus, QueueUpdate},
+};
+
+pub const MAX_PARTY_SIZE: u32 = 4;
+
+type Ticket = mpsc::Sender<Result<QueueUpdate, Status>>;
+type Pools = Arc<Mutex<HashMap<u32, Vec<Ticket>>>>;
+
+/// Players waiting for a match, pooled by the party size they asked for.
+/// Once a pool holds a full party a room sized for it is created and every
+/// player in the party joined to it.
+#[derive(Debug)]
+pub struct Matchmaker {
+    rooms: Arc<RoomRegistry>,
+    sessions: Arc<SessionStore>,
+    pools: Pools,
+}
+
+impl Matchmaker {
+    pub fn new(rooms: Arc<RoomRegistry>, sessions: Arc<SessionStore>) -> Self {
+        Self {
+            rooms,
+            sessions,
+            pools: Arc::default(),
+        }
+    }
+
+    /// Queues a player, returning the stream of their updates. Leaving the
+    /// queue is dropping the stream.
+    #[allow(clippy::result_large_err)]
+    pub async fn queue(&self, party_size: u32) -> Result<Queued, Status> {
+        if !(1..=MAX_PARTY_SIZE).contains(&party_size) {
+            return Err(Status::invalid_argument(format!(
+                "Party size must be between 1 and {MAX_PARTY_SIZE}"
+            )));
+        }
+
+        let (tx, rx) = mpsc::channel(4);
+        let parties = {
+            let mut pools = self.pools.lock().await;
+            let pool = pools.entry(party_size).or_default();
+
+            pool.retain(|ticket| !ticket.is_closed());
+            pool.push(tx);
+
+            let parties = take_parties(pool, party_size);
+            announce(pool, party_size);
+            parties
+        };
+
+        // Rooms are created without holding the pools, so queueing and
+        // leaving never wait on it.
+        for party in parties {
+            self.start_match(party_size, party).await;
+        }
+
+        Ok(Queued {
+            updates: rx,
+            pools: self.pools.clone(),
+            party_size,
+        })
+    }
+
+    async fn start_match(&self, party_size: u32, party: Vec<Ticket>) {
+        let room = match self
+            .rooms
+            .create(format!("Match of {party_size}"), Some(party_size))
+            .await
+        {
+            Ok(room) => room,
+            Err(status) => {
+                // The party stays together at the front of the queue and is
+                // matched again with the next player to queue.
+                warn!(%status, party_size, "Could not create a room for the match");
+                let mut pools = self.pools.lock().await;
+                let pool = pools.entry(party_size).or_default();
+                pool.splice(0..0, party.into_iter().filter(|ticket| !ticket.is_closed()));
+                announce(pool, party_size);
+                return;
+            }
+        };
+        info!(room_id = room.id, party_size, "Match found");
+
+        for ticket in party {
+            if ticket.is_closed() {
+                warn!(room_id = room.id, "Matched player left the queue");
+                continue;
+            }
+
+            let joined = room.join(&self.sessions, false).await;
+            let update = joined.map(|joined| QueueUpdate {
+                update: Some(Update::Matched(joined)),
+            });
+
+            if ticket.try_send(update).is_err() {
+                warn!(room_id = room.id, "Matched player left the queue");
+            }
+        }
+    }
+}
+
+/// Takes every full party out of the pool. A party someone left while it
+/// formed is broken up, the rest keep their place at the front of the queue.
+fn take_parties(pool: &mut Vec<Ticket>, party_size: u32) -> Vec<Vec<Ticket>> {
+    let mut parties = vec![];
+
+    while pool.len() as u32 >= party_size {
+        let party: Vec<Ticket> = pool.drain(..party_size as usize).collect();
+        if party.iter().any(Ticket::is_closed) {
+            let live = party.into_iter().filter(|ticket| !ticket.is_closed());
+            pool.splice(0..0, live);
+            continue;
+        }
+        parties.push(party);
+    }
+
+    parties
+}
+
+/// Tells everyone in the pool how many are waiting.
+fn announce(pool: &[Ticket], party_size: u32) {
+    let status = QueueUpdate {
+        update: Some(Update::Waiting(QueueStatus {
+            waiting: pool.len() as u32,
+            party_size,
+        })),
+    };
+
+    for ticket in pool {
+        // Statuses are skipped while the player has not read the previous
+        // ones, the last slot is always left for the match.
+        if ticket.capacity() > 1 {
+            let _ = ticket.try_send(Ok(status.clone()));
+        }
+    }
+}
+
+/// A player's updates while queued. Dropping it leaves the queue, and the
+/// players still waiting are told.
+#[derive(Debug)]
+pub struct Queued {
+    updates: mpsc::Receiver<Result<QueueUpdate, Status>>,
+    pools: Pools,
+    party_size: u32,
+}
+
+impl Stream for Queued {
+    type Item = Result<QueueUpdate, Status>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().updates.poll_recv(cx)
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        let pools = self.pools.clone();
+        let party_size = self.party_size;
+
+        // Same as for roster connections, the lock is taken on a task. The
+        // receiver is gone by the time it runs, closing this player's ticket.
+        tokio::spawn(async move {
+            let mut pools = pools.lock().await;
+            let Some(pool) = pools.get_mut(&party_size) else {
+                return;
+            };
+
+            let waiting = pool.len();
+            pool.retain(|ticket| !ticket.is_closed());
+            if pool.len() != waiting {
+                announce(pool, party_size);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room;
+    use tokio_stream::StreamExt;
+
+    fn matchmaker() -> Matchmaker {
+        matchmaker_with(room::tests::config())
+    }
+
+    fn matchmaker_with(config: room::RoomConfig) -> Matchmaker {
+        let rooms = RoomRegistry::new(config).unwrap();
+
+        Matchmaker::new(Arc::new(rooms), Arc::new(SessionStore::default()))
+    }
+
+    async fn next(queued: &mut Queued) -> Update {
+        queued.next().await.unwrap().unwrap().update.unwrap()
+    }
+
+    #[tokio::test]
+    async fn full_parties_are_joined_to_one_room() {
+        let matchmaker = matchmaker();
+
+        let mut first = matchmaker.queue(2).await.unwrap();
+        assert!(matches!(
+            next(&mut first).await,
+            Update::Waiting(QueueStatus { waiting: 1, .. })
+        ));
+
+        // Different party sizes never end up together.
+        let mut other = matchmaker.queue(3).await.unwrap();
+        let mut second = matchmaker.queue(2).await.unwrap();
+
+        let (Update::Matched(a), Update::Matched(b)) =
+            (next(&mut first).await, next(&mut second).await)
+        else {
+            panic!("both players should be matched");
+        };
+        assert_ne!(a.player_id, b.player_id);
+        assert_eq!(
+            a.room.as_ref().unwrap().room_id,
+            b.room.as_ref().unwrap().room_id
+        );
+        assert_eq!(b.room.unwrap().max_players, 2);
+
+        assert!(matches!(
+            next(&mut other).await,
+            Update::Waiting(QueueStatus { waiting: 1, .. })
+        ));
+        assert!(matchmaker.queue(5).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn leaving_the_queue_is_announced() {
+        let matchmaker = matchmaker();
+
+        let mut a = matchmaker.queue(3).await.unwrap();
+        let b = matchmaker.queue(3).await.unwrap();
+        assert!(matches!(
+            next(&mut a).await,
+            Update::Waiting(QueueStatus { waiting: 1, .. })
+        ));
+        assert!(matches!(
+            next(&mut a).await,
+            Update::Waiting(QueueStatus { waiting: 2, .. })
+        ));
+        drop(b);
+        assert!(matches!(
+            next(&mut a).await,
+            Update::Waiting(QueueStatus { waiting: 1, .. })
+        ));
+
+        // The player that left is not part of the next party.
+        let _c = matchmaker.queue(3).await.unwrap();
+        assert!(matches!(
+            next(&mut a).await,
+            Update::Waiting(QueueStatus { waiting: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn parties_without_a_room_stay_queued() {
+        // Only the default room fits, every match fails to get a room.
+        let matchmaker = matchmaker_with(room::RoomConfig {
+            max_rooms: 1,
+            ..room::tests::config()
+        });
+
+        let mut first = matchmaker.queue(1).await.unwrap();
+        assert!(matches!(
+            next(&mut first).await,
+            Update::Waiting(QueueStatus { waiting: 1, .. })
+        ));
+
+        // The next player retries the waiting party first, both stay queued.
+        let mut second = matchmaker.queue(1).await.unwrap();
+        assert!(matches!(
+            next(&mut second).await,
+            Update::Waiting(QueueStatus { waiting: 2, .. })
+        ));
+        loop {
+            match next(&mut first).await {
+                Update::Waiting(QueueStatus { waiting: 2, .. }) => break,
+                Update::Waiting(_) => {}
+                Update::Matched(_) => panic!("no room should be created"),
+            }
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use tokio::{
+    sync::{broadcast, mpsc, watch, Mutex, RwLock},
+    task::AbortHandle,
+    time::{self, Duration, Instant},
+};
+use tonic::Status;
+use tracing::{error, info, info_span, Instrument};
+
+use crate::{
+    session::SessionStore,
+    simulation::{
+        aggregation::AggregationPolicy,
+        publish,
+        roster::{Roster, SharedRoster},
+        split_control::{SharedSplitControl, SplitControl},
+        ControlMode, Simulation, SimulationContext,
+    },
+    updates::{
+        simulation_event::Event, ChatMessage, InstructionUpdate, JoinResponse, PlayerJoined,
+        RoomInfo, SimulationEvent, SimulationUpdate, WorldDescription,
+    },
+};
+
+pub const DEFAULT_ROOM_ID: &str = "default";
+
+const CHAT_CAPACITY: usize = 64;
+
+/// Settings every room's simulation is created with, and how many rooms
+/// there may be.
+#[derive(Debug, Clone)]
+pub struct RoomConfig {
+    /// Simulated time per physics step, the simulation steps as often.
+    pub physics_timestep: Duration,
+    pub snapshot_interval: Duration,
+    pub instruction_interval: Duration,
+    pub player_idle_timeout: Duration,
+    /// How long a disconnected player keeps their place and can resume.
+    pub session_grace_period: Duration,
+    /// How far behind the live simulation spectators are shown it, so a
+    /// streamed view cannot be used to help the players.
+    pub spectator_delay: Duration,
+    pub aggregation_policy: AggregationPolicy,
+    pub split_control: bool,
+    pub pause_when_empty: bool,
+    /// How long a room stays open without players or subscribers.
+    pub room_idle_timeout: Duration,
+    /// Rooms open at once, the default room included.
+    pub max_rooms: usize,
+    /// How long spectator sessions last. Unlike players, spectators are not
+    /// in the roster, so nothing else ends their sessions.
+    pub spectator_session_ttl: Duration,
+}
+
+/// A running simulation and the channels to talk to it.
+#[derive(Debug)]
+pub struct Room {
+    pub id: String,
+    pub name: String,
+    pub sim_tx: broadcast::Sender<SimulationEvent>,
+    /// Chat for clients that only want chat, so they neither count as
+    /// simulation subscribers nor lose messages to a burst of snapshots.
+    pub chat_tx: broadcast::Sender<ChatMessage>,
+    pub snapshot_rx: watch::Receiver<SimulationUpdate>,
+    pub world_rx: watch::Receiver<WorldDescription>,
+    pub ins_tx: mpsc::Sender<InstructionUpdate>,
+    pub split_control: Option<SharedSplitControl>,
+    pub roster: SharedRoster,
+    pub spectator_delay: Duration,
+    pub max_players: Option<u32>,
+    task: AbortHandle,
+}
+
+impl Room {
+    /// Starts the room's simulation on its own task.
+    fn spawn(
+        id: String,
+        name: String,
+        max_players: Option<u32>,
+        config: &RoomConfig,
+    ) -> Result<Self> {
+        let mut ctx = SimulationContext::with_timestep(config.physics_timestep.as_secs_f32())?;
+        let (sim_tx, _) = broadcast::channel::<SimulationEvent>(10);
+        let (chat_tx, _) = broadcast::channel::<ChatMessage>(CHAT_CAPACITY);
+        let (ins_tx, ins_rx) = mpsc::channel::<InstructionUpdate>(1000);
+        let (snapshot_tx, snapshot_rx) = watch::channel(SimulationUpdate::default());
+        let (world_tx, world_rx) = watch::channel(WorldDescription::default());
+
+        let control_mode = if config.split_control {
+            ControlMode::Split(SplitControl::shared(config.player_idle_timeout))
+        } else {
+            ControlMode::Cooperative(config.aggregation_policy.clone())
+        };
+        let split_control = match &control_mode {
+            ControlMode::Split(split) => Some(split.clone()),
+            ControlMode::Cooperative(_) => None,
+        };
+
+        let roster = Roster::shared(config.session_grace_period);
+
+        let mut sim = Simulation::new(
+            sim_tx.clone(),
+            snapshot_tx,
+            world_tx,
+            config.snapshot_interval,
+            ins_rx,
+            config.instruction_interval,
+            control_mode,
+            roster.clone(),
+        );
+        sim.set_pause_when_empty(config.pause_when_empty);
+
+        let task = tokio::spawn(
+            async move {
+                info!("Starting simulation thread");
+                if let Err(e) = sim.run(&mut ctx).await {
+                    error!(err=%e, "Simulation failed");
+                }
+            }
+            .instrument(info_span!("room", room_id = id)),
+        )
+        .abort_handle();
+
+        Ok(Self {
+            id,
+            name,
+            sim_tx,
+            chat_tx,
+            snapshot_rx,
+            world_rx,
+            ins_tx,
+            split_control,
+            roster,
+            spectator_delay: config.spectator_delay,
+            max_players,
+            task,
+        })
+    }
+
+    pub async fn info(&self) -> RoomInfo {
+        let roster = self.roster.lock().await;
+
+        RoomInfo {
+            room_id: self.id.clone(),
+            name: self.name.clone(),
+            subscribers: self.sim_tx.receiver_count() as u32,
+            spectators: roster.spectators(),
+            players: roster.player_count(),
+            max_players: self.max_players.unwrap_or_default(),
+        }
+    }
+
+    /// Whether nobody is in the room or watching it, chat included.
+    async fn is_idle(&self) -> bool {
+        let roster = self.roster.lock().await;
+
+        roster.player_count() == 0
+            && roster.spectators() == 0
+            && self.sim_tx.receiver_count() == 0
+            && self.chat_tx.receiver_count() == 0
+    }
+
+    /// Issues a session for a new player or spectator in this room. Players
+    /// are refused once the room is full.
+    pub async fn join(
+        &self,
+        sessions: &SessionStore,
+        spectator: bool,
+    ) -> Result<JoinResponse, Status> {
+        if spectator {
+            let (session_token, session) = sessions.issue(self.id.clone(), true);
+            info!(
+                player_id = session.player_id,
+                room_id = self.id,
+                "Spectator joined"
+            );
+
+            return Ok(JoinResponse {
+                player_id: session.player_id,
+                session_token,
+                room: Some(self.info().await),
+            });
+        }
+
+        let mut roster = self.roster.lock().await;
+        if self
+            .max_players
+            .is_some_and(|max| roster.player_count() >= max)
+        {
+            return Err(Status::resource_exhausted(format!(
+                "Room {} is full",
+                self.id
+            )));
+        }
+
+        let (session_token, session) = sessions.issue(self.id.clone(), false);
+        roster.join(&session.player_id);
+        drop(roster);
+        info!(
+            player_id = session.player_id,
+            room_id = self.id,
+            "Player joined"
+        );
+
+        let joined = PlayerJoined {
+            player_id: session.player_id.clone(),
+        };
+        publish(&self.sim_tx, Event::PlayerJoined(joined));
+
+        Ok(JoinResponse {
+            player_id: session.player_id,
+            session_token,
+            room: Some(self.info().await),
+        })
+    }
+}
+
+/// Every room on the server keyed by id. A default room always exists for
+/// clients that do not pick one, every other room is closed once it has been
+/// idle for the configured timeout.
+#[derive(Debug)]
+pub struct RoomRegistry {
+    config: RoomConfig,
+    rooms: RwLock<HashMap<String, Arc<Room>>>,
+    /// When each idle room was first seen idle.
+    idle_since: Mutex<HashMap<String, Instant>>,
+    next_id: AtomicU64,
+}
+
+impl RoomRegistry {
+    /// Fails if the config cannot run a simulation, checked by starting the
+    /// default room with it.
+    pub fn new(config: RoomConfig) -> Result<Self> {
+        let default = Room::spawn(
+            DEFAULT_ROOM_ID.to_string(),
+            "Default".to_string(),
+            None,
+            &config,
+        )?;
+
+        Ok(Self {
+            config,
+            rooms: RwLock::new(HashMap::from([(default.id.clone(), Arc::new(default))])),
+            idle_since: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    pub async fn create(
+        &self,
+        name: String,
+        max_players: Option<u32>,
+    ) -> Result<Arc<Room>, Status> {
+        let mut rooms = self.rooms.write().await;
+        if rooms.len() >= self.config.max_rooms {
+            return Err(Status::resource_exhausted(
+                "Too many rooms open, try again later",
+            ));
+        }
+
+        let id = format!("room-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let room = Room::spawn(id.clone(), name, max_players, &self.config)
+            .map(Arc::new)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        info!(room_id = id, "Room created");
+        rooms.insert(id, room.clone());
+        Ok(room)
+    }
+
+    /// Closes every room that has been idle for the timeout, stopping its
+    /// simulation, and returns their ids. The default room is never closed.
+    pub async fn close_idle(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut rooms = self.rooms.write().await;
+        let mut idle_since = self.idle_since.lock().await;
+        let mut closed = vec![];
+
+        for room in rooms.values() {
+            if room.id == DEFAULT_ROOM_ID {
+                continue;
+            }
+            if !room.is_idle().await {
+                idle_since.remove(&room.id);
+                continue;
+            }
+
+            let since = *idle_since.entry(room.id.clone()).or_insert(now);
+            if now.duration_since(since) >= self.config.room_idle_timeout {
+                closed.push(room.id.clone());
+            }
+        }
+
+        for room_id in &closed {
+            idle_since.remove(room_id);
+            if let Some(room) = rooms.remove(room_id) {
+                room.task.abort();
+                info!(room_id, "Room closed");
+            }
+        }
+
+        closed
+    }
+
+    /// Revokes sessions that can no longer be used: those of players that
+    /// left their room or whose room closed, and spectator sessions older
+    /// than their time to live. Returns how many were revoked.
+    pub async fn expire_sessions(&self, sessions: &SessionStore) -> usize {
+        let now = Instant::now();
+        let mut revoked = 0;
+
+        for (token, session, issued) in sessions.list() {
+            let live = match self.get(&session.room_id).await {
+                Some(_) if session.spectator => {
+                    now.duration_since(issued) < self.config.spectator_session_ttl
+                }
+                Some(room) => room.roster.lock().await.contains(&session.player_id),
+                None => false,
+            };
+
+            if !live {
+                sessions.revoke(&token);
+                revoked += 1;
+            }
+        }
+
+        revoked
+    }
+
+    /// Closes idle rooms and expires sessions every `period`, for as long as
+    /// the server runs.
+    pub async fn clean_up_every(self: Arc<Self>, sessions: Arc<SessionStore>, period: Duration) {
+        let mut interval = time::interval(period);
+        loop {
+            interval.tick().await;
+            self.close_idle().await;
+
+            let revoked = self.expire_sessions(&sessions).await;
+            if revoked > 0 {
+                info!(revoked, "Expired sessions revoked");
+            }
+        }
+    }
+
+    /// Looks up a room, an empty id meaning the default room.
+    pub async fn get(&self, room_id: &str) -> Option<Arc<Room>> {
+        let room_id = if room_id.is_empty() {
+            DEFAULT_ROOM_ID
+        } else {
+            room_id
+        };
+
+        self.rooms.read().await.get(room_id).cloned()
+    }
+
+    /// Same as [`Self::get`] for RPCs, an unknown room is `NotFound`.
+    pub async fn find(&self, room_id: &str) -> Result<Arc<Room>, Status> {
+        self.get(room_id)
+            .await
+            .ok_or_else(|| Status::not_found(format!("No room with id {room_id}")))
+    }
+
+    pub async fn list(&self) -> Vec<RoomInfo> {
+        let mut rooms = vec![];
+        for room in self.rooms.read().await.values() {
+            rooms.push(room.info().await);
+        }
+        rooms.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+        rooms
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub fn config() -> RoomConfig {
+        RoomConfig {
+            physics_timestep: Duration::from_secs_f64(1. / 120.),
+            snapshot_interval: Duration::from_millis(33),
+            instruction_interval: Duration::from_millis(200),
+            player_idle_timeout: Duration::from_secs(30),
+            session_grace_period: Duration::from_secs(20),
+            spectator_delay: Duration::ZERO,
+            aggregation_policy: Default::default(),
+            split_control: false,
+            pause_when_empty: false,
+            room_idle_timeout: Duration::ZERO,
+            max_rooms: 3,
+            spectator_session_ttl: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_rooms_are_closed() {
+        let rooms = RoomRegistry::new(config()).unwrap();
+        let sessions = SessionStore::default();
+
+        let empty = rooms.create("Empty".to_string(), None).await.unwrap();
+        let joined = rooms.create("Joined".to_string(), None).await.unwrap();
+        joined.join(&sessions, false).await.unwrap();
+
+        assert_eq!(rooms.close_idle().await, vec![empty.id.clone()]);
+        assert!(rooms.get(&empty.id).await.is_none());
+        assert!(rooms.get(&joined.id).await.is_some());
+        assert!(rooms.get(DEFAULT_ROOM_ID).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn rooms_are_capped() {
+        let rooms = RoomRegistry::new(config()).unwrap();
+
+        rooms.create("First".to_string(), None).await.unwrap();
+        rooms.create("Second".to_string(), None).await.unwrap();
+        let status = rooms.create("Third".to_string(), None).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn full_rooms_refuse_players() {
+        let rooms = RoomRegistry::new(config()).unwrap();
+        let sessions = SessionStore::default();
+
+        let room = rooms.create("Pair".to_string(), Some(2)).await.unwrap();
+        room.join(&sessions, false).await.unwrap();
+        room.join(&sessions, false).await.unwrap();
+
+        let status = room.join(&sessions, false).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(room.info().await.players, 2);
+
+        // Spectators do not take a player's place.
+        room.join(&sessions, true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_with_their_room() {
+        let rooms = RoomRegistry::new(config()).unwrap();
+        let sessions = SessionStore::default();
+
+        let room = rooms.create("Room".to_string(), None).await.unwrap();
+        let player = room.join(&sessions, false).await.unwrap();
+        room.join(&sessions, true).await.unwrap();
+        assert_eq!(rooms.expire_sessions(&sessions).await, 0);
+        assert!(sessions.get(&player.session_token).is_some());
+
+        rooms.rooms.write().await.remove(&room.id);
+        assert_eq!(rooms.expire_sessions(&sessions).await, 2);
+        assert!(sessions.get(&player.session_token).is_none());
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_with_their_player_or_ttl() {
+        let rooms = RoomRegistry::new(RoomConfig {
+            session_grace_period: Duration::ZERO,
+            spectator_session_ttl: Duration::ZERO,
+            ..config()
+        })
+        .unwrap();
+        let sessions = SessionStore::default();
+
+        let room = rooms.find(DEFAULT_ROOM_ID).await.unwrap();
+        let player = room.join(&sessions, false).await.unwrap();
+        let spectator = room.join(&sessions, true).await.unwrap();
+        room.roster.lock().await.expire_away();
+
+        assert_eq!(rooms.expire_sessions(&sessions).await, 2);
+        assert!(sessions.get(&player.session_token).is_none());
+        assert!(sessions.get(&spectator.session_token).is_none());
+    }
+}
@@ -1 +1,6 @@
+pub mod broadcast_delay;
+pub mod chat_service;
+pub mod room_service;
 pub mod simulation_service;
+pub mod subscription;
+pub mod transform_encoding;
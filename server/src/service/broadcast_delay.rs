@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+
+use tokio::time::{Duration, Instant};
+
+use crate::updates::SimulationEvent;
+
+/// Holds events back for a fixed time before they are sent. Order is kept,
+/// so a delayed subscriber sees exactly what a live one did, only later.
+#[derive(Debug)]
+pub struct BroadcastDelay {
+    delay: Duration,
+    queue: VecDeque<(Instant, SimulationEvent)>,
+}
+
+impl BroadcastDelay {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// When the oldest held event is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.front().map(|(due, _)| *due)
+    }
+
+    /// Queues the events and returns every event that is due, which without
+    /// a delay are the events themselves.
+    pub fn delay(&mut self, events: Vec<SimulationEvent>) -> Vec<SimulationEvent> {
+        self.delay_at(events, Instant::now())
+    }
+
+    fn delay_at(&mut self, events: Vec<SimulationEvent>, now: Instant) -> Vec<SimulationEvent> {
+        let due = now + self.delay;
+        self.queue
+            .extend(events.into_iter().map(|event| (due, event)));
+
+        let ready = self.queue.iter().take_while(|(due, _)| *due <= now).count();
+        self.queue.drain(..ready).map(|(_, event)| event).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::updates::{simulation_event::Event, GameState};
+
+    fn event() -> SimulationEvent {
+        Event::GameState(GameState::default()).into()
+    }
+
+    #[test]
+    fn events_are_held_for_the_delay() {
+        let start = Instant::now();
+        let mut delay = BroadcastDelay::new(Duration::from_millis(500));
+
+        assert!(delay.delay_at(vec![event(), event()], start).is_empty());
+        assert_eq!(delay.next_due(), Some(start + Duration::from_millis(500)));
+
+        let later = start + Duration::from_millis(300);
+        assert!(delay.delay_at(vec![event()], later).is_empty());
+
+        let due = delay.delay_at(vec![], start + Duration::from_millis(500));
+        assert_eq!(due.len(), 2);
+        assert_eq!(delay.next_due(), Some(later + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn no_delay_passes_events_through() {
+        let mut delay = BroadcastDelay::new(Duration::ZERO);
+
+        assert_eq!(delay.delay(vec![event()]).len(), 1);
+        assert_eq!(delay.next_due(), None);
+    }
+}
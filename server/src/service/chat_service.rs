@@ -0,0 +1,82 @@
+use std::{pin::Pin, sync::Arc};
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::Stream;
+use tonic::{async_trait, Request, Response, Status};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    room::RoomRegistry,
+    session::{requested_room, require_session},
+    simulation::publish,
+    updates::{
+        chat_service_server::ChatService, simulation_event::Event, BatchedChatMessages,
+        ChatMessage, ChatSubscriptionRequest, GenericResponse,
+    },
+};
+
+/// Chat travels on the simulation stream as events, this service only exists
+/// for clients that want chat without the simulation. They get it from the
+/// room's own chat channel.
+#[derive(Debug, Clone)]
+pub struct ChatUpdateService {
+    rooms: Arc<RoomRegistry>,
+}
+
+impl ChatUpdateService {
+    pub fn new(rooms: Arc<RoomRegistry>) -> Self {
+        Self { rooms }
+    }
+}
+
+#[async_trait]
+impl ChatService for ChatUpdateService {
+    type SubscribeToChatStream =
+        Pin<Box<dyn Stream<Item = Result<BatchedChatMessages, Status>> + Send + Sync + 'static>>;
+
+    #[instrument(skip_all)]
+    async fn subscribe_to_chat(
+        &self,
+        request: Request<ChatSubscriptionRequest>,
+    ) -> Result<Response<Self::SubscribeToChatStream>, Status> {
+        let room_id = requested_room(&request, &request.get_ref().room_id);
+        let room = self.rooms.find(&room_id).await?;
+        info!(room_id = room.id, "New chat subscriber");
+        let mut chat_rx = room.chat_tx.subscribe();
+
+        let outgoing = async_stream::try_stream! {
+            loop {
+                match chat_rx.recv().await {
+                    Ok(chat) => yield BatchedChatMessages { chat: vec![chat] },
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Chat subscriber lagged, messages dropped");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(outgoing)))
+    }
+
+    #[instrument(skip_all)]
+    async fn send_chat(
+        &self,
+        request: Request<ChatMessage>,
+    ) -> Result<Response<GenericResponse>, Status> {
+        let session = require_session(&request)?;
+        let mut chat = request.into_inner();
+        chat.user_id = session.player_id;
+        chat.room_id = session.room_id;
+
+        if chat.chat.trim().is_empty() {
+            return Err(Status::invalid_argument("Chat message is empty"));
+        }
+
+        let room = self.rooms.find(&chat.room_id).await?;
+        publish(&room.chat_tx, chat.clone());
+        publish(&room.sim_tx, Event::Chat(chat));
+
+        Ok(Response::new(GenericResponse { ok: true }))
+    }
+}
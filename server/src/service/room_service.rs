@@ -0,0 +1,164 @@
+use std::{pin::Pin, sync::Arc};
+
+use tokio_stream::Stream;
+use tonic::{async_trait, Request, Response, Status};
+use tracing::{info, instrument};
+
+use crate::{
+    matchmaking::Matchmaker,
+    room::RoomRegistry,
+    session::{unknown_session, SessionStore},
+    updates::{
+        room_service_server::RoomService, CreateRoomRequest, GenericRequest, GetRoomRequest,
+        JoinRequest, JoinResponse, QueueRequest, QueueUpdate, RoomInfo, RoomList,
+    },
+};
+
+#[derive(Debug, Clone)]
+pub struct RoomUpdateService {
+    rooms: Arc<RoomRegistry>,
+    sessions: Arc<SessionStore>,
+    matchmaker: Arc<Matchmaker>,
+}
+
+impl RoomUpdateService {
+    pub fn new(rooms: Arc<RoomRegistry>, sessions: Arc<SessionStore>) -> Self {
+        let matchmaker = Arc::new(Matchmaker::new(rooms.clone(), sessions.clone()));
+
+        Self {
+            rooms,
+            sessions,
+            matchmaker,
+        }
+    }
+}
+
+impl RoomUpdateService {
+    /// Hands an earlier session back to a player that is still within their
+    /// grace period, keeping their place in the room. Spectators have no
+    /// place to keep and can always resume.
+    async fn resume(&self, session_token: String) -> Result<JoinResponse, Status> {
+        let session = self
+            .sessions
+            .get(&session_token)
+            .ok_or_else(unknown_session)?;
+        let room = self.rooms.find(&session.room_id).await?;
+
+        if !session.spectator && !room.roster.lock().await.contains(&session.player_id) {
+            self.sessions.revoke(&session_token);
+            return Err(Status::unauthenticated("Session expired, join again"));
+        }
+        info!(
+            player_id = session.player_id,
+            room_id = room.id,
+            "Player resumed"
+        );
+
+        Ok(JoinResponse {
+            player_id: session.player_id,
+            session_token,
+            room: Some(room.info().await),
+        })
+    }
+}
+
+#[async_trait]
+impl RoomService for RoomUpdateService {
+    type QueueForMatchStream =
+        Pin<Box<dyn Stream<Item = Result<QueueUpdate, Status>> + Send + Sync + 'static>>;
+
+    #[instrument(skip_all)]
+    async fn create_room(
+        &self,
+        request: Request<CreateRoomRequest>,
+    ) -> Result<Response<RoomInfo>, Status> {
+        let request = request.into_inner();
+        let max_players = (request.max_players > 0).then_some(request.max_players);
+        let room = self.rooms.create(request.name, max_players).await?;
+        Ok(Response::new(room.info().await))
+    }
+
+    /// Confirms the room exists without joining it, the returned id is what
+    /// the other services expect as `room_id`.
+    #[instrument(skip_all)]
+    async fn get_room(
+        &self,
+        request: Request<GetRoomRequest>,
+    ) -> Result<Response<RoomInfo>, Status> {
+        let room_id = request.into_inner().room_id;
+        let room = self.rooms.find(&room_id).await?;
+
+        Ok(Response::new(room.info().await))
+    }
+
+    #[instrument(skip_all)]
+    async fn list_rooms(
+        &self,
+        _request: Request<GenericRequest>,
+    ) -> Result<Response<RoomList>, Status> {
+        Ok(Response::new(RoomList {
+            rooms: self.rooms.list().await,
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn join(&self, request: Request<JoinRequest>) -> Result<Response<JoinResponse>, Status> {
+        let request = request.into_inner();
+        if !request.session_token.is_empty() {
+            return self.resume(request.session_token).await.map(Response::new);
+        }
+
+        let room = self.rooms.find(&request.room_id).await?;
+        let joined = room.join(&self.sessions, request.spectator).await?;
+
+        Ok(Response::new(joined))
+    }
+
+    #[instrument(skip_all)]
+    async fn queue_for_match(
+        &self,
+        request: Request<QueueRequest>,
+    ) -> Result<Response<Self::QueueForMatchStream>, Status> {
+        let party_size = request.into_inner().party_size;
+        let queued = self.matchmaker.queue(party_size).await?;
+        info!(party_size, "Player queued for a match");
+
+        Ok(Response::new(Box::pin(queued)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::{self, DEFAULT_ROOM_ID};
+
+    fn service() -> RoomUpdateService {
+        let rooms = RoomRegistry::new(room::tests::config()).unwrap();
+        RoomUpdateService::new(Arc::new(rooms), Arc::new(SessionStore::default()))
+    }
+
+    #[tokio::test]
+    async fn only_join_enters_a_room() {
+        let service = service();
+        let get = |room_id: &str| {
+            Request::new(GetRoomRequest {
+                room_id: room_id.into(),
+            })
+        };
+
+        let info = service.get_room(get("")).await.unwrap().into_inner();
+        assert_eq!(info.room_id, DEFAULT_ROOM_ID);
+        assert_eq!(info.players, 0);
+        let status = service.get_room(get("room-9")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let joined = service
+            .join(Request::new(JoinRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(joined.room.unwrap().players, 1);
+        let info = service.get_room(get("")).await.unwrap().into_inner();
+        assert_eq!(info.players, 1);
+    }
+}
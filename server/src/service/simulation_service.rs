@@ -1,62 +1,237 @@
-use std::{pin::Pin, time::Duration};
+use std::{pin::Pin, sync::Arc};
 
 use tokio::{
-    sync::{broadcast, mpsc},
-    time,
+    select,
+    sync::{broadcast::error::RecvError, mpsc::error::TrySendError},
+    time::{self, Duration},
 };
-use tokio_stream::{Stream, StreamExt};
+use tokio_stream::Stream;
 use tonic::{async_trait, Request, Response, Status};
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
 
+use super::{broadcast_delay::BroadcastDelay, subscription::Subscription};
 use crate::{
-    simulation::instruction,
+    room::RoomRegistry,
+    session::{requested_room, require_session, Session},
+    simulation::{
+        instruction::ControlInput, roster::Connection, split_control::SharedSplitControl,
+    },
     updates::{
-        simulation_service_server::{SimulationService, SimulationServiceServer},
-        GenericRequest, GenericResponse, InstructionUpdate, SimulationUpdate,
+        simulation_event::Event, simulation_service_server::SimulationService, ControlAssignment,
+        GenericResponse, InputState, InstructionUpdate, SimulationEvent, SpectatorCount,
+        SubscriptionRequest,
     },
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SimulationUpdateService {
-    sim_rx: broadcast::Receiver<SimulationUpdate>,
-    ins_tx: mpsc::Sender<InstructionUpdate>,
+    rooms: Arc<RoomRegistry>,
 }
 
 impl SimulationUpdateService {
-    pub fn new(
-        sim_rx: broadcast::Receiver<SimulationUpdate>,
-        ins_tx: mpsc::Sender<InstructionUpdate>,
-    ) -> Self {
-        Self { sim_rx, ins_tx }
+    pub fn new(rooms: Arc<RoomRegistry>) -> Self {
+        Self { rooms }
+    }
+}
+
+/// Current owners of the instructions, empty outside split control.
+async fn current_assignments(split_control: &Option<SharedSplitControl>) -> Vec<ControlAssignment> {
+    match split_control {
+        Some(split) => split.lock().await.assignments(),
+        None => vec![],
     }
 }
 
 #[async_trait]
 impl SimulationService for SimulationUpdateService {
     type SubscribeToSimulationStream =
-        Pin<Box<dyn Stream<Item = Result<SimulationUpdate, Status>> + Send + Sync + 'static>>;
+        Pin<Box<dyn Stream<Item = Result<SimulationEvent, Status>> + Send + Sync + 'static>>;
 
     #[instrument(skip_all)]
     async fn subscribe_to_simulation(
         &self,
-        request: Request<GenericRequest>,
+        request: Request<SubscriptionRequest>,
     ) -> Result<Response<Self::SubscribeToSimulationStream>, Status> {
-        info!("New subscriber");
-        let mut sim_rx1 = self.sim_rx.resubscribe();
+        let room_id = requested_room(&request, &request.get_ref().room_id);
+        let session = request.extensions().get::<Session>().cloned();
+        let request = request.into_inner();
+        info!(?request, room_id, "New subscriber");
+        let room = self.rooms.find(&room_id).await?;
+        // Subscribing from the sender keeps the receiver count equal to the
+        // number of connected clients.
+        let mut sim_rx1 = room.sim_tx.subscribe();
+
+        // Players watching their own room count as connected until the stream
+        // is dropped, which starts their grace period. Everyone else is a
+        // spectator and sees the room with its spectator delay.
+        let connection = match session.filter(|s| s.room_id == room.id && !s.spectator) {
+            Some(session) => Connection::open(&room.roster, &session.player_id)
+                .await
+                .ok_or_else(|| Status::unauthenticated("Session expired, join again"))?,
+            None => Connection::spectate(&room.roster).await,
+        };
+        let mut delayed = BroadcastDelay::new(if connection.is_spectator() {
+            room.spectator_delay
+        } else {
+            Duration::ZERO
+        });
+        let spectators = room.roster.lock().await.spectators();
+
+        // The world is published once the simulation has built its level.
+        let world = room
+            .world_rx
+            .clone()
+            .wait_for(|world| !world.colliders.is_empty())
+            .await
+            .map(|world| world.clone())
+            .map_err(|_| Status::unavailable("Simulation is not running"))?;
+
+        // Late joiners would otherwise not see bodies until they next move or
+        // the next keyframe, nor learn who controls what until the next change.
+        let mut subscription = Subscription::new(&request, &world);
+        let mut snapshot = room.snapshot_rx.borrow().clone();
+        snapshot.control_assignments = current_assignments(&room.split_control).await;
+        let initial = subscription.start(snapshot);
+
+        let snapshot_rx = room.snapshot_rx.clone();
+        let split_control = room.split_control.clone();
 
         let outgoing = async_stream::try_stream! {
-            while let Ok(update) = sim_rx1.recv().await {
-                yield update;
+            let _connection = connection;
+            yield Event::LevelChanged(world).into();
+            yield Event::Spectators(SpectatorCount { count: spectators }).into();
+
+            // Spectators must not get a live look at the world either.
+            for event in delayed.delay(vec![initial]) {
+                yield event;
+            }
+
+            loop {
+                let received = match delayed.next_due() {
+                    Some(due) => select! {
+                        received = sim_rx1.recv() => Some(received),
+                        _ = time::sleep_until(due) => None,
+                    },
+                    None => Some(sim_rx1.recv().await),
+                };
+
+                let events = match received {
+                    Some(Ok(event)) => subscription.push(event),
+                    // Catching up on stale deltas is pointless, skip to the
+                    // newest update and start over from a full snapshot.
+                    Some(Err(RecvError::Lagged(skipped))) => {
+                        sim_rx1 = sim_rx1.resubscribe();
+
+                        let mut snapshot = snapshot_rx.borrow().clone();
+                        snapshot.control_assignments = current_assignments(&split_control).await;
+                        let event = subscription.resync(snapshot);
+
+                        warn!(
+                            skipped,
+                            lag_events = subscription.lag_events(),
+                            "Subscriber lagged, resyncing"
+                        );
+                        vec![event]
+                    }
+                    Some(Err(RecvError::Closed)) => break,
+                    None => vec![],
+                };
+
+                for event in delayed.delay(events) {
+                    yield event;
+                }
             }
         };
 
         Ok(Response::new(Box::pin(outgoing)))
     }
 
+    #[instrument(skip_all)]
     async fn send_instruction(
         &self,
         instruction_req: Request<InstructionUpdate>,
     ) -> Result<Response<GenericResponse>, Status> {
-        todo!();
+        let session = require_session(&instruction_req)?;
+        if session.spectator {
+            return Err(Status::permission_denied(
+                "Spectators cannot send instructions",
+            ));
+        }
+        let mut update = instruction_req.into_inner();
+        update.player_id = session.player_id;
+        update.room_id = session.room_id;
+
+        let input =
+            ControlInput::try_from(&update).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let room = self.rooms.find(&update.room_id).await?;
+
+        if !room.roster.lock().await.contains(&update.player_id) {
+            return Err(Status::unauthenticated("Session expired, join again"));
+        }
+
+        if let Some(split) = &room.split_control {
+            let mut split = split.lock().await;
+            split.touch(&update.player_id);
+
+            // Releasing is always allowed so ownership changes cannot leave
+            // an input stuck.
+            if let Some(instruction) = input
+                .instructions()
+                .into_iter()
+                .filter(|_| update.state() != InputState::Release)
+                .find(|ins| !split.owns(&update.player_id, *ins))
+            {
+                return Err(Status::permission_denied(format!(
+                    "{instruction:?} is controlled by another player"
+                )));
+            }
+        }
+
+        // Never wait on a full channel, a stalled simulation would otherwise
+        // hang the caller and, over WebSocket, their whole connection.
+        room.ins_tx.try_send(update).map_err(|e| match e {
+            TrySendError::Full(_) => {
+                warn!("Instruction channel full, dropping instruction.");
+                Status::resource_exhausted("Simulation is busy, instruction dropped")
+            }
+            TrySendError::Closed(_) => {
+                error!("Failed to forward instruction to simulation.");
+                Status::unavailable("Simulation is not accepting instructions")
+            }
+        })?;
+
+        Ok(Response::new(GenericResponse { ok: true }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        room::{self, DEFAULT_ROOM_ID},
+        session::SessionStore,
+    };
+
+    #[tokio::test]
+    async fn only_subscribers_without_a_player_session_spectate() {
+        let rooms = Arc::new(RoomRegistry::new(room::tests::config()).unwrap());
+        let service = SimulationUpdateService::new(rooms.clone());
+        let room = rooms.find(DEFAULT_ROOM_ID).await.unwrap();
+        let sessions = SessionStore::default();
+        let joined = room.join(&sessions, false).await.unwrap();
+
+        let mut request = Request::new(SubscriptionRequest::default());
+        request
+            .extensions_mut()
+            .insert(sessions.get(&joined.session_token).unwrap());
+        let _player = service.subscribe_to_simulation(request).await.unwrap();
+        assert!(room.roster.lock().await.any_connected());
+        assert_eq!(room.roster.lock().await.spectators(), 0);
+
+        let _anonymous = service
+            .subscribe_to_simulation(Request::new(SubscriptionRequest::default()))
+            .await
+            .unwrap();
+        assert_eq!(room.roster.lock().await.spectators(), 1);
     }
 }
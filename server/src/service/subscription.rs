@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+
+use tokio::time::{Duration, Instant};
+
+use super::transform_encoding::{self, Bounds};
+use crate::updates::{
+    simulation_event::Event, EntityDespawned, EntitySpawned, SimulationEvent, SimulationUpdate,
+    SubscriptionRequest, TransformEncoding, WorldDescription,
+};
+
+/// What a single subscriber asked for. Broadcast updates are merged until the
+/// subscriber's rate allows another one, then filtered down to the fields and
+/// entities it wants.
+#[derive(Debug)]
+pub struct Subscription {
+    encoding: TransformEncoding,
+    period: Duration,
+    include_events: bool,
+    include_debug: bool,
+    include_velocities: bool,
+    entity_ids: HashSet<u64>,
+    bounds: Bounds,
+    pending: Option<SimulationUpdate>,
+    last_sent: Option<Instant>,
+    lag_events: u32,
+}
+
+impl Subscription {
+    pub fn new(request: &SubscriptionRequest, world: &WorldDescription) -> Self {
+        let period = match request.update_rate_hz {
+            0 => Duration::ZERO,
+            hz => Duration::from_secs(1) / hz,
+        };
+
+        Self {
+            encoding: request.encoding(),
+            period,
+            include_events: request.include_events,
+            include_debug: request.include_debug,
+            include_velocities: request.include_velocities,
+            entity_ids: request.entity_ids.iter().copied().collect(),
+            bounds: Bounds::from(world),
+            pending: None,
+            last_sent: None,
+            lag_events: 0,
+        }
+    }
+
+    /// Takes the next broadcast event and returns the events due for the
+    /// subscriber. Snapshots are held back to the subscriber's rate, anything
+    /// else goes out right away behind the snapshot it follows.
+    pub fn push(&mut self, event: SimulationEvent) -> Vec<SimulationEvent> {
+        self.push_at(event, Instant::now())
+    }
+
+    fn push_at(&mut self, event: SimulationEvent, now: Instant) -> Vec<SimulationEvent> {
+        match event.event {
+            Some(Event::Snapshot(update)) => {
+                match self.pending.as_mut() {
+                    Some(pending) => merge(pending, update),
+                    None => self.pending = Some(update),
+                }
+
+                let due = self
+                    .last_sent
+                    .is_none_or(|last| now.duration_since(last) >= self.period);
+
+                if due {
+                    self.flush(now).into_iter().collect()
+                } else {
+                    vec![]
+                }
+            }
+            Some(event) if self.wants(&event) => {
+                let mut events: Vec<_> = self.flush(now).into_iter().collect();
+                events.push(event.into());
+                events
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Replaces whatever is pending with a full snapshot, the first thing a
+    /// subscriber gets after the world so bodies that are not moving show up
+    /// before the next keyframe.
+    pub fn start(&mut self, snapshot: SimulationUpdate) -> SimulationEvent {
+        self.pending = None;
+        self.last_sent = Some(Instant::now());
+        Event::Snapshot(self.filter(snapshot)).into()
+    }
+
+    /// Same as [`Self::start`] after the subscriber fell behind and missed
+    /// updates.
+    pub fn resync(&mut self, snapshot: SimulationUpdate) -> SimulationEvent {
+        self.lag_events += 1;
+        self.start(snapshot)
+    }
+
+    pub fn lag_events(&self) -> u32 {
+        self.lag_events
+    }
+
+    fn flush(&mut self, now: Instant) -> Option<SimulationEvent> {
+        let update = self.pending.take()?;
+        self.last_sent = Some(now);
+        Some(Event::Snapshot(self.filter(update)).into())
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        match event {
+            Event::EntitySpawned(EntitySpawned { id, .. })
+            | Event::EntityDespawned(EntityDespawned { id }) => {
+                self.entity_ids.is_empty() || self.entity_ids.contains(id)
+            }
+            _ => true,
+        }
+    }
+
+    fn filter(&self, mut update: SimulationUpdate) -> SimulationUpdate {
+        if !self.entity_ids.is_empty() {
+            update
+                .spatial_updates
+                .retain(|spatial| self.entity_ids.contains(&spatial.id));
+        }
+
+        if !self.include_events {
+            update.acks.clear();
+            update.control_assignments.clear();
+        }
+
+        if !self.include_debug {
+            update.debug = None;
+        }
+
+        update.lag_events = self.lag_events;
+
+        for spatial in update.spatial_updates.iter_mut() {
+            if !self.include_velocities {
+                spatial.linvel = None;
+                spatial.angvel = None;
+            }
+
+            if self.encoding == TransformEncoding::Compact {
+                transform_encoding::compact(spatial, &self.bounds);
+            }
+        }
+
+        update
+    }
+}
+
+/// Folds `update` into an update that has not been sent yet, so nothing the
+/// subscriber would have seen in the skipped update is lost.
+fn merge(pending: &mut SimulationUpdate, update: SimulationUpdate) {
+    // A keyframe holds every body, older deltas are redundant.
+    if update.keyframe {
+        pending.spatial_updates.clear();
+        pending.keyframe = true;
+    }
+
+    for spatial in update.spatial_updates {
+        match pending
+            .spatial_updates
+            .iter_mut()
+            .find(|s| s.id == spatial.id)
+        {
+            Some(existing) => *existing = spatial,
+            None => pending.spatial_updates.push(spatial),
+        }
+    }
+
+    for ack in update.acks {
+        match pending
+            .acks
+            .iter_mut()
+            .find(|a| a.player_id == ack.player_id)
+        {
+            Some(existing) => *existing = ack,
+            None => pending.acks.push(ack),
+        }
+    }
+
+    // Assignments are only sent on change and always complete.
+    if !update.control_assignments.is_empty() {
+        pending.control_assignments = update.control_assignments;
+    }
+
+    pending.tick = update.tick;
+    pending.server_time_ms = update.server_time_ms;
+    pending.debug = update.debug;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::updates::{InputAck, PlayerJoined, SpatialData};
+
+    fn spatial(id: u64, grounded: bool) -> SpatialData {
+        SpatialData {
+            id,
+            grounded,
+            ..Default::default()
+        }
+    }
+
+    fn subscription(request: SubscriptionRequest) -> Subscription {
+        Subscription::new(&request, &WorldDescription::default())
+    }
+
+    fn snapshot(update: SimulationUpdate) -> SimulationEvent {
+        Event::Snapshot(update).into()
+    }
+
+    fn only_snapshot(mut events: Vec<SimulationEvent>) -> SimulationUpdate {
+        assert_eq!(events.len(), 1);
+        match events.pop().and_then(|e| e.event) {
+            Some(Event::Snapshot(update)) => update,
+            event => panic!("expected a snapshot, got {event:?}"),
+        }
+    }
+
+    #[test]
+    fn skipped_updates_are_merged() {
+        let mut sub = subscription(SubscriptionRequest {
+            update_rate_hz: 10,
+            include_events: true,
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        assert_eq!(sub.push_at(snapshot(Default::default()), start).len(), 1);
+
+        let first = SimulationUpdate {
+            spatial_updates: vec![spatial(1, false), spatial(2, false)],
+            acks: vec![InputAck {
+                player_id: "a".into(),
+                sequence: 1,
+            }],
+            tick: 1,
+            ..Default::default()
+        };
+        let second = SimulationUpdate {
+            spatial_updates: vec![spatial(1, true)],
+            acks: vec![InputAck {
+                player_id: "a".into(),
+                sequence: 2,
+            }],
+            tick: 2,
+            ..Default::default()
+        };
+
+        assert!(sub
+            .push_at(snapshot(first), start + Duration::from_millis(33))
+            .is_empty());
+        let merged =
+            only_snapshot(sub.push_at(snapshot(second), start + Duration::from_millis(100)));
+
+        assert_eq!(merged.tick, 2);
+        assert_eq!(
+            merged.spatial_updates,
+            vec![spatial(1, true), spatial(2, false)]
+        );
+        assert_eq!(merged.acks.len(), 1);
+        assert_eq!(merged.acks[0].sequence, 2);
+    }
+
+    #[test]
+    fn unrequested_fields_and_entities_are_dropped() {
+        let mut sub = subscription(SubscriptionRequest {
+            entity_ids: vec![2],
+            ..Default::default()
+        });
+
+        let update = only_snapshot(sub.push(snapshot(SimulationUpdate {
+            spatial_updates: vec![spatial(1, false), spatial(2, false)],
+            acks: vec![InputAck::default()],
+            debug: Some(Default::default()),
+            ..Default::default()
+        })));
+
+        assert_eq!(update.spatial_updates, vec![spatial(2, false)]);
+        assert!(update.acks.is_empty());
+        assert!(update.debug.is_none());
+
+        let despawned = |id| Event::EntityDespawned(EntityDespawned { id }).into();
+        assert!(sub.push(despawned(1)).is_empty());
+        assert_eq!(sub.push(despawned(2)).len(), 1);
+    }
+
+    #[test]
+    fn events_are_not_held_back_by_the_rate() {
+        let mut sub = subscription(SubscriptionRequest {
+            update_rate_hz: 1,
+            ..Default::default()
+        });
+
+        assert_eq!(sub.push(snapshot(Default::default())).len(), 1);
+        assert!(sub.push(snapshot(Default::default())).is_empty());
+
+        // The pending snapshot goes first to keep the order.
+        let joined: SimulationEvent = Event::PlayerJoined(PlayerJoined::default()).into();
+        let events = sub.push(joined.clone());
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0].event, Some(Event::Snapshot(_))));
+        assert_eq!(events[1], joined);
+    }
+
+    #[test]
+    fn resync_drops_pending_and_counts_lag() {
+        let mut sub = subscription(SubscriptionRequest {
+            update_rate_hz: 1,
+            ..Default::default()
+        });
+
+        assert_eq!(sub.push(snapshot(Default::default())).len(), 1);
+        assert!(sub
+            .push(snapshot(SimulationUpdate {
+                spatial_updates: vec![spatial(1, false)],
+                ..Default::default()
+            }))
+            .is_empty());
+
+        let update = only_snapshot(vec![sub.resync(SimulationUpdate {
+            spatial_updates: vec![spatial(1, true)],
+            keyframe: true,
+            ..Default::default()
+        })]);
+
+        assert_eq!(update.spatial_updates, vec![spatial(1, true)]);
+        assert_eq!(update.lag_events, 1);
+        assert_eq!(sub.lag_events(), 1);
+        assert!(sub.push(snapshot(Default::default())).is_empty());
+    }
+
+    #[test]
+    fn start_sends_the_keyframe_without_counting_lag() {
+        let mut sub = subscription(SubscriptionRequest {
+            update_rate_hz: 1,
+            ..Default::default()
+        });
+
+        let update = only_snapshot(vec![sub.start(SimulationUpdate {
+            spatial_updates: vec![spatial(1, true)],
+            keyframe: true,
+            ..Default::default()
+        })]);
+
+        assert!(update.keyframe);
+        assert_eq!(update.lag_events, 0);
+        // The keyframe counts against the rate like any other update.
+        assert!(sub.push(snapshot(Default::default())).is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use crate::updates::{Coordinates, Orientation, SpatialData, WorldDescription};
+
+const POSITION_STEPS: f32 = u16::MAX as f32;
+const COMPONENT_BITS: u32 = 10;
+const COMPONENT_STEPS: f32 = ((1 << COMPONENT_BITS) - 1) as f32;
+
+/// Region compact positions are spread over, taken from the world description.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+impl Bounds {
+    pub fn new(min: [f32; 3], max: [f32; 3]) -> Self {
+        Self { min, max }
+    }
+}
+
+impl From<&WorldDescription> for Bounds {
+    fn from(world: &WorldDescription) -> Self {
+        let corner =
+            |c: &Option<Coordinates>| c.as_ref().map(|c| [c.x, c.y, c.z]).unwrap_or_default();
+
+        Self::new(corner(&world.bounds_min), corner(&world.bounds_max))
+    }
+}
+
+/// Swaps full precision coordinates and orientation for the compact encoding.
+pub fn compact(spatial: &mut SpatialData, bounds: &Bounds) {
+    if let (Some(coor), Some(orient)) = (spatial.coordinates.take(), spatial.orientation.take()) {
+        spatial.compact_transform = encode(&coor, &orient, bounds);
+    }
+}
+
+/// Fixed point positions relative to the bounds followed by a smallest-three
+/// quaternion, ten bytes in total.
+pub fn encode(coor: &Coordinates, orient: &Orientation, bounds: &Bounds) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(10);
+
+    for (axis, v) in [coor.x, coor.y, coor.z].into_iter().enumerate() {
+        let extent = bounds.max[axis] - bounds.min[axis];
+        let t = if extent > 0. {
+            ((v - bounds.min[axis]) / extent).clamp(0., 1.)
+        } else {
+            0.
+        };
+        bytes.extend_from_slice(&((t * POSITION_STEPS).round() as u16).to_le_bytes());
+    }
+
+    let mut q = [orient.i, orient.j, orient.k, orient.w];
+    let largest = (0..4)
+        .max_by(|a, b| q[*a].abs().total_cmp(&q[*b].abs()))
+        .unwrap_or(3);
+
+    // q and -q are the same rotation, keeping the dropped component positive
+    // lets the decoder recover it from the other three.
+    if q[largest] < 0. {
+        q.iter_mut().for_each(|c| *c = -*c);
+    }
+
+    let packed = (0..4)
+        .filter(|i| *i != largest)
+        .fold(largest as u32, |acc, i| {
+            let t = ((q[i] + FRAC_1_SQRT_2) / (2. * FRAC_1_SQRT_2)).clamp(0., 1.);
+            (acc << COMPONENT_BITS) | (t * COMPONENT_STEPS).round() as u32
+        });
+    bytes.extend_from_slice(&packed.to_le_bytes());
+
+    bytes
+}
+
+/// Inverse of [`encode`], clients do the same to unpack transforms.
+#[cfg(test)]
+pub fn decode(bytes: &[u8], bounds: &Bounds) -> Option<(Coordinates, Orientation)> {
+    if bytes.len() != 10 {
+        return None;
+    }
+
+    let mut pos = [0.; 3];
+    for (axis, chunk) in bytes[..6].chunks_exact(2).enumerate() {
+        let t = u16::from_le_bytes([chunk[0], chunk[1]]) as f32 / POSITION_STEPS;
+        pos[axis] = bounds.min[axis] + t * (bounds.max[axis] - bounds.min[axis]);
+    }
+
+    let mut packed = u32::from_le_bytes(bytes[6..10].try_into().ok()?);
+    let largest = (packed >> (3 * COMPONENT_BITS)) as usize;
+    let mut q = [0.; 4];
+    for i in (0..4).rev().filter(|i| *i != largest) {
+        let t = (packed & ((1 << COMPONENT_BITS) - 1)) as f32 / COMPONENT_STEPS;
+        q[i] = t * 2. * FRAC_1_SQRT_2 - FRAC_1_SQRT_2;
+        packed >>= COMPONENT_BITS;
+    }
+    q[largest] = (1. - q.iter().map(|c| c * c).sum::<f32>()).max(0.).sqrt();
+
+    Some((
+        Coordinates {
+            x: pos[0],
+            y: pos[1],
+            z: pos[2],
+        },
+        Orientation {
+            i: q[0],
+            j: q[1],
+            k: q[2],
+            w: q[3],
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use rapier3d::prelude::nalgebra::{Quaternion, UnitQuaternion, Vector3};
+
+    use super::*;
+
+    #[test]
+    fn round_trip_error_is_bounded() {
+        let bounds = Bounds::new([-66., -16., -66.], [66., 41., 66.]);
+        let max_pos_err = (0..3)
+            .map(|a| (bounds.max[a] - bounds.min[a]) / POSITION_STEPS)
+            .fold(0., f32::max);
+
+        // Deterministic spread of positions and rotations.
+        let mut seed = 0x2545_f491_u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+
+        for _ in 0..10_000 {
+            let coor = Coordinates {
+                x: bounds.min[0] + next() * 132.,
+                y: bounds.min[1] + next() * 57.,
+                z: bounds.min[2] + next() * 132.,
+            };
+            let axis = Vector3::new(next() - 0.5, next() - 0.5, next() - 0.5);
+            let rot = UnitQuaternion::from_scaled_axis(axis.normalize() * next() * TAU);
+            let orient = Orientation {
+                i: rot.i,
+                j: rot.j,
+                k: rot.k,
+                w: rot.w,
+            };
+
+            let bytes = encode(&coor, &orient, &bounds);
+            assert_eq!(bytes.len(), 10);
+
+            let (d_coor, d_orient) = decode(&bytes, &bounds).unwrap();
+            assert!((coor.x - d_coor.x).abs() <= max_pos_err);
+            assert!((coor.y - d_coor.y).abs() <= max_pos_err);
+            assert!((coor.z - d_coor.z).abs() <= max_pos_err);
+
+            let decoded = UnitQuaternion::from_quaternion(Quaternion::new(
+                d_orient.w, d_orient.i, d_orient.j, d_orient.k,
+            ));
+            assert!(rot.angle_to(&decoded) < 0.005, "{rot:?} vs {decoded:?}");
+        }
+    }
+
+    #[test]
+    fn positions_outside_bounds_are_clamped() {
+        let bounds = Bounds::new([-1.; 3], [1.; 3]);
+        let coor = Coordinates {
+            x: 5.,
+            y: -5.,
+            z: 0.,
+        };
+        let orient = Orientation {
+            w: 1.,
+            ..Default::default()
+        };
+
+        let (d_coor, _) = decode(&encode(&coor, &orient, &bounds), &bounds).unwrap();
+        assert_eq!((d_coor.x, d_coor.y), (1., -1.));
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use rand::RngCore;
+use tokio::time::Instant;
+use tonic::{service::Interceptor, Request, Status};
+
+const TOKEN_BYTES: usize = 32;
+
+/// Who a caller is, attached to requests carrying a valid session token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub player_id: String,
+    pub room_id: String,
+    /// Watching only, never in control of anything.
+    pub spectator: bool,
+}
+
+/// Sessions handed out by `Join`, keyed by their secret token, with when
+/// they were issued.
+#[derive(Debug, Default)]
+pub struct SessionStore {
+    sessions: RwLock<HashMap<String, (Session, Instant)>>,
+    next_player: AtomicU64,
+}
+
+impl SessionStore {
+    /// Creates a new player or spectator in the room and returns the token
+    /// identifying them.
+    pub fn issue(&self, room_id: String, spectator: bool) -> (String, Session) {
+        let mut bytes = [0u8; TOKEN_BYTES];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+
+        let session = Session {
+            player_id: format!(
+                "player-{}",
+                self.next_player.fetch_add(1, Ordering::Relaxed) + 1
+            ),
+            room_id,
+            spectator,
+        };
+
+        self.sessions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(token.clone(), (session.clone(), Instant::now()));
+
+        (token, session)
+    }
+
+    pub fn get(&self, token: &str) -> Option<Session> {
+        self.sessions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(token)
+            .map(|(session, _)| session.clone())
+    }
+
+    /// Every token with its session and when it was issued.
+    pub fn list(&self) -> Vec<(String, Session, Instant)> {
+        self.sessions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(token, (session, issued))| (token.clone(), session.clone(), *issued))
+            .collect()
+    }
+
+    pub fn revoke(&self, token: &str) {
+        self.sessions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(token);
+    }
+}
+
+/// Reads `authorization: Bearer <token>` on every call and attaches the
+/// matching [`Session`] to the request. Calls without a token pass through
+/// anonymously, a token that is unknown or expired is rejected so the client
+/// learns it has to join again.
+#[derive(Debug, Clone)]
+pub struct SessionInterceptor {
+    sessions: Arc<SessionStore>,
+}
+
+impl SessionInterceptor {
+    pub fn new(sessions: Arc<SessionStore>) -> Self {
+        Self { sessions }
+    }
+}
+
+impl Interceptor for SessionInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(header) = request.metadata().get("authorization") else {
+            return Ok(request);
+        };
+
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Malformed authorization header"))?;
+        let session = self.sessions.get(token).ok_or_else(unknown_session)?;
+
+        request.extensions_mut().insert(session);
+        Ok(request)
+    }
+}
+
+/// Status for a presented token that matches no session, on every transport.
+pub fn unknown_session() -> Status {
+    Status::unauthenticated("Unknown or expired session token, join again without it")
+}
+
+/// The caller's session, required for anything acting on behalf of a player.
+#[allow(clippy::result_large_err)]
+pub fn require_session<T>(request: &Request<T>) -> Result<Session, Status> {
+    request
+        .extensions()
+        .get::<Session>()
+        .cloned()
+        .ok_or_else(|| {
+            Status::unauthenticated("No valid session token, join a room to get one first")
+        })
+}
+
+/// The room a request is about: the one it names, otherwise the caller's own.
+pub fn requested_room<T>(request: &Request<T>, room_id: &str) -> String {
+    match request.extensions().get::<Session>() {
+        Some(session) if room_id.is_empty() => session.room_id.clone(),
+        _ => room_id.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with(header: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", header.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn interceptor_attaches_the_session() {
+        let sessions = Arc::new(SessionStore::default());
+        let (token, session) = sessions.issue("room-1".into(), false);
+        let mut interceptor = SessionInterceptor::new(sessions);
+
+        let request = interceptor
+            .call(request_with(&format!("Bearer {token}")))
+            .unwrap();
+        assert_eq!(require_session(&request).unwrap(), session);
+        assert_eq!(requested_room(&request, ""), "room-1");
+        assert_eq!(requested_room(&request, "room-2"), "room-2");
+
+        let anonymous = interceptor.call(Request::new(())).unwrap();
+        assert!(require_session(&anonymous).is_err());
+    }
+
+    #[test]
+    fn unknown_tokens_are_rejected() {
+        let sessions = Arc::new(SessionStore::default());
+        let (token, _) = sessions.issue("room-1".into(), false);
+        sessions.revoke(&token);
+        let mut interceptor = SessionInterceptor::new(sessions);
+
+        for header in [
+            format!("Bearer {token}"),
+            "Bearer nope".into(),
+            "nope".into(),
+        ] {
+            let status = interceptor.call(request_with(&header)).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+    }
+}
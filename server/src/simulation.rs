@@ -1,18 +1,42 @@
-use std::f32::consts::PI;
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::updates::{Coordinates, InstructionUpdate, Orientation, SimulationUpdate, SpatialData};
-use anyhow::Result;
+use crate::updates::{
+    simulation_event::Event, ControlAssignment, Coordinates, DebugInfo, GameState, GameStatus,
+    InputAck, InputState, InstructionUpdate, Orientation, PlayerLeft, SimulationEvent,
+    SimulationUpdate, SpatialData, SpectatorCount, WorldDescription,
+};
+use aggregation::{AggregationPolicy, InstructionAggregator};
+use anyhow::{ensure, Result};
+use delta::DeltaEncoder;
+use entity::entity_id;
+use held_input::HeldInputs;
+use instruction::{ControlInput, InstructionHandler};
+use level::Level;
 use nalgebra::{vector, Vector3};
 use rapier3d::prelude::*;
+use roster::SharedRoster;
+use split_control::SharedSplitControl;
+use timestep::FixedTimestep;
 use tokio::{
     select,
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch},
     time,
 };
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, trace};
 
+pub mod aggregation;
+pub mod delta;
+pub mod entity;
+pub mod held_input;
 pub mod instruction;
 pub mod level;
+pub mod roster;
+pub mod split_control;
+pub mod timestep;
 
 // half extents
 const GROUND_DIM_HE: [f32; 3] = [50., 0.05, 50.];
@@ -24,125 +48,400 @@ const PAWN_MASS: f32 = 20.;
 const MAX_ANGULAR_VEL: f32 = 2. * PI;
 const MAX_LINEAR_VEL: f32 = 10.;
 
+// snapshots between full keyframes
+const KEYFRAME_INTERVAL: u64 = 30;
+
+const DEFAULT_DT: f32 = 1. / 120.;
+const DEFAULT_MAX_SUBSTEPS: u32 = 8;
+
+// minimum upward component of a contact normal for it to support a body
+const GROUND_NORMAL_MIN_Y: f32 = 0.7;
+
+/// How instructions from the connected players drive the shared pawn.
+#[derive(Debug, Clone)]
+pub enum ControlMode {
+    /// Everyone steers everything, blended by the policy.
+    Cooperative(AggregationPolicy),
+    /// Every player owns a subset of the instructions.
+    Split(SharedSplitControl),
+}
+
 #[derive(Debug, Clone)]
 enum Action {
+    Step,
     ApplyInstruction,
     SendUpdate,
 }
 
 pub struct Simulation {
-    channel: broadcast::Sender<SimulationUpdate>,
-    update_interval: time::Duration,
+    channel: broadcast::Sender<SimulationEvent>,
+    snapshot_channel: watch::Sender<SimulationUpdate>,
+    world_channel: watch::Sender<WorldDescription>,
+    snapshot_interval: time::Duration,
     instructions_channel: mpsc::Receiver<InstructionUpdate>,
     instruction_interval: time::Duration,
+    aggregator: InstructionAggregator,
+    split_control: Option<SharedSplitControl>,
+    pending_assignments: Vec<ControlAssignment>,
+    roster: SharedRoster,
+    spectators: u32,
+    held: HeldInputs,
+    acks: HashMap<String, u32>,
+    /// Acks for taps waiting in the aggregator, released once it resolves.
+    pending_acks: HashMap<String, u32>,
+    /// Physics steps taken so far.
+    tick: u64,
+    delta: DeltaEncoder,
+    pause_when_empty: bool,
+    /// Last seen in the roster, refreshed every instruction tick.
+    players_connected: bool,
+    paused: bool,
 }
 
 impl Simulation {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        channel: broadcast::Sender<SimulationUpdate>,
-        update_interval_ms: time::Duration,
+        channel: broadcast::Sender<SimulationEvent>,
+        snapshot_channel: watch::Sender<SimulationUpdate>,
+        world_channel: watch::Sender<WorldDescription>,
+        snapshot_interval_ms: time::Duration,
         instructions_channel: mpsc::Receiver<InstructionUpdate>,
         instruction_interval_ms: time::Duration,
+        control_mode: ControlMode,
+        roster: SharedRoster,
     ) -> Self {
+        let (aggregator, split_control) = match control_mode {
+            ControlMode::Cooperative(policy) => (InstructionAggregator::new(policy), None),
+            ControlMode::Split(split) => (InstructionAggregator::default(), Some(split)),
+        };
+
         Self {
             channel,
-            update_interval: update_interval_ms,
+            snapshot_channel,
+            world_channel,
+            snapshot_interval: snapshot_interval_ms,
             instructions_channel,
             instruction_interval: instruction_interval_ms,
+            aggregator,
+            split_control,
+            pending_assignments: vec![],
+            roster,
+            spectators: 0,
+            held: HeldInputs::default(),
+            acks: HashMap::new(),
+            pending_acks: HashMap::new(),
+            tick: 0,
+            delta: DeltaEncoder::new(KEYFRAME_INTERVAL),
+            pause_when_empty: false,
+            players_connected: false,
+            paused: false,
         }
     }
 
+    /// Stops stepping physics while no player is connected, picking up where
+    /// it left off once one subscribes again. Spectators alone do not keep
+    /// the simulation running.
+    pub fn set_pause_when_empty(&mut self, pause_when_empty: bool) {
+        self.pause_when_empty = pause_when_empty;
+    }
+
+    fn update_paused(&mut self) -> bool {
+        let paused = self.pause_when_empty && !self.players_connected;
+
+        if paused != self.paused {
+            if paused {
+                info!("No players connected, pausing simulation");
+            } else {
+                info!("Player connected, resuming simulation");
+            }
+            self.paused = paused;
+        }
+
+        paused
+    }
+
     #[instrument(skip_all)]
     pub async fn run(&mut self, ctx: &mut SimulationContext) -> Result<()> {
-        let (mut r_set, mut c_set, pawn_handle) = Self::initialize_world();
+        let mut level = Self::initialize_world();
+        let pawn_handle = level.get_pawn_handles()[0];
+        self.world_channel.send_replace(level.describe());
         let mut phys_pipeline = PhysicsPipeline::new();
+        let mut timestep = ctx.timestep();
 
-        let mut update_interval = time::interval(self.update_interval);
+        // Waking once per step keeps the accumulator from batching steps up,
+        // it still catches up if a wake is late.
+        let mut physics_interval = time::interval(timestep.dt());
+        let mut snapshot_interval = time::interval(self.snapshot_interval);
         let mut ins_interval = time::interval(self.instruction_interval);
 
-        for _i in 0.. {
+        for iteration in 0u64.. {
+            let paused = self.update_paused();
             let res = select! {
                 biased;
-                _ = update_interval.tick() => {
-                    Self::step(&mut phys_pipeline, &mut r_set, &mut c_set, ctx);
-                    Some(Action::SendUpdate)
+                _ = physics_interval.tick() => {
+                    let steps = if paused {
+                        timestep.reset();
+                        self.discard_instructions();
+                        0
+                    } else {
+                        timestep.advance()
+                    };
+                    for _ in 0..steps {
+                        let body = &mut level.get_rigid_body_set_mut()[pawn_handle];
+                        let grounded = ctx.is_grounded(body);
+                        self.receive_instructions(body, grounded);
+                        self.apply_held_inputs(body, grounded, ctx.integration_parameters.dt);
+                        Self::step(&mut phys_pipeline, &mut level, ctx);
+                        self.tick += 1;
+                    }
+                    (steps > 0).then_some(Action::Step)
                 },
+                _ = snapshot_interval.tick() => Some(Action::SendUpdate),
                 _ = ins_interval.tick() => Some(Action::ApplyInstruction),
             };
 
             let should_log = false;
             if should_log {
-                info!(step = _i, "Simulation loop ongoing");
+                info!(iteration, tick = self.tick, "Simulation loop ongoing");
                 info!(channel_size = self.channel.len());
                 info!("Action: {res:?}");
             }
 
-            let body = &mut r_set[pawn_handle];
-            let trans = body.translation();
-
-            if trans.y < -10. {
-                //break;
-                body.set_position(
-                    Isometry::new(
-                        Vector3::new(PAWN_START[0], PAWN_START[1], PAWN_START[2]),
-                        *body.position().rotation.axis_angle().unwrap().0,
-                    ),
-                    true,
-                );
-
-                body.set_linvel(vector![0., 0., 0.], true);
-            }
+            Self::respawn_fallen_pawns(&mut level);
 
             match res {
                 Some(Action::ApplyInstruction) => {
-                    self.send_update(body, should_log)?;
+                    let body = &mut level.get_rigid_body_set_mut()[pawn_handle];
+                    let grounded = ctx.is_grounded(body);
+                    self.apply_instructions(body, grounded).await;
                 }
                 Some(Action::SendUpdate) => {
-                    self.send_update(body, should_log)?;
+                    self.send_update(&level, ctx, timestep.overruns(), should_log);
                 }
                 _ => {}
             }
         }
 
         info!("Simulation loop complete");
-        self.channel.send(SimulationUpdate {
-            spatial_updates: vec![],
-            done: Some(true),
-        })?;
+        self.publish(Event::GameState(GameState {
+            status: GameStatus::Finished.into(),
+        }));
 
         Ok(())
     }
 
     #[instrument(skip_all)]
-    fn send_update(&mut self, body: &mut RigidBody, should_log: bool) -> Result<()> {
-        // takes a single body but want to support any number in future
-        let trans = body.translation();
-        let rot = body.rotation();
-
-        let coor = Coordinates {
-            x: trans.x,
-            y: trans.y,
-            z: trans.z,
+    fn receive_instructions(&mut self, body: &mut RigidBody, grounded: bool) {
+        while let Ok(update) = self.instructions_channel.try_recv() {
+            let control = match ControlInput::try_from(&update) {
+                Ok(control) => control,
+                Err(e) => {
+                    // Invalid instructions are acknowledged too, replaying
+                    // them would not make them valid.
+                    self.ack(&update, false);
+                    error!(err=%e, "Dropping invalid instruction.");
+                    continue;
+                }
+            };
+            let Some(input) = &update.input else {
+                continue;
+            };
+
+            match update.state() {
+                InputState::Press => self.held.press(update.player_id.clone(), input, control),
+                InputState::Release => self.held.release(&update.player_id, input),
+                // Owned instructions never conflict, so they apply as they come.
+                InputState::Tap if self.split_control.is_some() => {
+                    body.apply_input(&control.grounded(grounded))
+                }
+                InputState::Tap => {
+                    self.aggregator.push(update.player_id.clone(), control);
+                    self.ack(&update, true);
+                    continue;
+                }
+            }
+            self.ack(&update, false);
+        }
+    }
+
+    /// Drops instructions sent while paused, so they do not pile up and all
+    /// apply at once on resume.
+    fn discard_instructions(&mut self) {
+        while let Ok(update) = self.instructions_channel.try_recv() {
+            trace!(
+                player_id = update.player_id,
+                "Discarding instruction while paused."
+            );
+        }
+    }
+
+    /// Acknowledges an instruction once it affected the simulation. Taps
+    /// waiting in the aggregator are only acknowledged when it resolves, and
+    /// since an ack covers every earlier sequence, so is anything the player
+    /// sent after them.
+    fn ack(&mut self, update: &InstructionUpdate, pending: bool) {
+        let acks = if pending || self.pending_acks.contains_key(&update.player_id) {
+            &mut self.pending_acks
+        } else {
+            &mut self.acks
         };
 
-        let orient = Orientation {
-            i: rot.i,
-            j: rot.j,
-            k: rot.k,
-            w: rot.w,
+        let ack = acks.entry(update.player_id.clone()).or_default();
+        *ack = update.sequence.max(*ack);
+    }
+
+    fn apply_held_inputs(&self, body: &mut RigidBody, grounded: bool, dt: f32) {
+        let held = self.held.per_player();
+
+        let input = if self.split_control.is_some() {
+            Some(
+                held.values()
+                    .fold(ControlInput::default(), |acc, input| acc.combined(input)),
+            )
+        } else {
+            self.aggregator.blend(&held)
         };
 
-        let spatial_data = SpatialData {
-            id: 1,
-            coordinates: Some(coor),
-            orientation: Some(orient),
+        if let Some(input) = input.filter(|input| !input.is_idle()) {
+            body.apply_held_input(&input.grounded(grounded), dt);
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn apply_instructions(&mut self, body: &mut RigidBody, grounded: bool) {
+        if let Some(input) = self.aggregator.resolve() {
+            body.apply_input(&input.grounded(grounded));
+        }
+        for (player_id, sequence) in std::mem::take(&mut self.pending_acks) {
+            let ack = self.acks.entry(player_id).or_default();
+            *ack = sequence.max(*ack);
+        }
+
+        let (away, left, spectators) = {
+            let mut roster = self.roster.lock().await;
+            let left = roster.expire_away();
+            let away: Vec<String> = roster.away().map(String::from).collect();
+            self.players_connected = roster.any_connected();
+            (away, left, roster.spectators())
         };
 
+        if spectators != self.spectators {
+            self.spectators = spectators;
+            self.publish(Event::Spectators(SpectatorCount { count: spectators }));
+        }
+
+        // The pawn idles while its players are away instead of running on
+        // whatever they held when the connection dropped.
+        self.held
+            .retain(|player_id, _| !away.iter().chain(&left).any(|p| p == player_id));
+        for player_id in &left {
+            info!(player_id, "Player left");
+            self.acks.remove(player_id);
+            self.pending_acks.remove(player_id);
+            self.publish(Event::PlayerLeft(PlayerLeft {
+                player_id: player_id.clone(),
+            }));
+        }
+
+        if let Some(split) = self.split_control.clone() {
+            let mut split = split.lock().await;
+            for player_id in &away {
+                split.hold(player_id);
+            }
+            for player_id in &left {
+                split.remove(player_id);
+            }
+            split.expire_idle();
+
+            if let Some(assignments) = split.take_changes() {
+                info!(players = assignments.len(), "Control assignments changed");
+                self.pending_assignments = assignments;
+
+                // Inputs held across an ownership change belong to someone else now.
+                self.held.retain(|player_id, input| {
+                    input
+                        .instructions()
+                        .iter()
+                        .all(|ins| split.owns(player_id, *ins))
+                });
+            }
+        }
+    }
+
+    fn respawn_fallen_pawns(level: &mut Level) {
+        for handle in level.get_pawn_handles().clone() {
+            let body = &mut level.get_rigid_body_set_mut()[handle];
+
+            if body.translation().y < -10. {
+                body.set_position(
+                    Isometry::from_parts(
+                        vector![PAWN_START[0], PAWN_START[1], PAWN_START[2]].into(),
+                        *body.rotation(),
+                    ),
+                    true,
+                );
+
+                body.set_linvel(vector![0., 0., 0.], true);
+            }
+        }
+    }
+
+    #[instrument(skip_all)]
+    fn send_update(
+        &mut self,
+        level: &Level,
+        ctx: &SimulationContext,
+        tick_overruns: u64,
+        should_log: bool,
+    ) {
+        let keyframe = self.delta.begin();
+        let mut spatial_updates = vec![];
+        let mut snapshot = vec![];
+
+        for (handle, body) in level
+            .get_rigid_body_set()
+            .iter()
+            .filter(|(_, body)| body.is_dynamic() || body.is_kinematic())
+        {
+            let grounded = ctx.is_grounded(body);
+            let spatial = spatial_data(handle, body, grounded);
+
+            if self
+                .delta
+                .should_send(keyframe, entity_id(handle), body, grounded)
+            {
+                spatial_updates.push(spatial.clone());
+            }
+            snapshot.push(spatial);
+        }
+
         let sim_up = SimulationUpdate {
-            spatial_updates: vec![spatial_data],
-            done: None,
+            spatial_updates,
+            control_assignments: std::mem::take(&mut self.pending_assignments),
+            acks: self
+                .acks
+                .iter()
+                .map(|(player_id, sequence)| InputAck {
+                    player_id: player_id.clone(),
+                    sequence: *sequence,
+                })
+                .collect(),
+            tick: self.tick,
+            server_time_ms: server_time_ms(),
+            debug: Some(DebugInfo { tick_overruns }),
+            keyframe,
+            ..Default::default()
         };
 
+        // Published before the delta so a subscriber resyncing from it never
+        // misses the changes the delta carries.
+        self.snapshot_channel.send_replace(SimulationUpdate {
+            spatial_updates: snapshot,
+            control_assignments: vec![],
+            keyframe: true,
+            ..sim_up.clone()
+        });
+
         if should_log {
             info!(
                 channel_len = self.channel.len(),
@@ -150,19 +449,18 @@ impl Simulation {
             );
         }
 
-        self.channel.send(sim_up).map(|_| ()).map_err(|e| {
-            error!(err=%e, "Failed to send simulation update.");
-            anyhow::anyhow!(e)
-        })
+        self.publish(Event::Snapshot(sim_up));
+    }
+
+    fn publish(&self, event: Event) {
+        // The simulation carries on without subscribers so late joiners find
+        // a running world.
+        publish(&self.channel, event);
     }
 
     #[instrument(skip_all)]
-    fn step(
-        phys_pipeline: &mut PhysicsPipeline,
-        rigid_body_set: &mut RigidBodySet,
-        collider_set: &mut ColliderSet,
-        ctx: &mut SimulationContext,
-    ) {
+    fn step(phys_pipeline: &mut PhysicsPipeline, level: &mut Level, ctx: &mut SimulationContext) {
+        let (rigid_body_set, collider_set) = level.get_sets_mut();
         phys_pipeline.step(
             &ctx.gravity,
             &ctx.integration_parameters,
@@ -180,7 +478,7 @@ impl Simulation {
         );
     }
 
-    fn initialize_world() -> (RigidBodySet, ColliderSet, RigidBodyHandle) {
+    fn initialize_world() -> Level {
         let mut rigid_body_set = RigidBodySet::new();
         let mut collider_set = ColliderSet::new();
 
@@ -190,7 +488,8 @@ impl Simulation {
             .restitution(1.)
             .build();
 
-        collider_set.insert_with_parent(ground, ground_handle, &mut rigid_body_set);
+        let ground_collider =
+            collider_set.insert_with_parent(ground, ground_handle, &mut rigid_body_set);
 
         let pawn_rigid_boy = RigidBodyBuilder::dynamic()
             .translation(vector![PAWN_START[0], PAWN_START[1], PAWN_START[2]])
@@ -201,12 +500,68 @@ impl Simulation {
             .mass(PAWN_MASS)
             .restitution(1.)
             .build();
-        collider_set.insert_with_parent(pawn_collider, pawn_handle, &mut rigid_body_set);
+        let pawn_collider =
+            collider_set.insert_with_parent(pawn_collider, pawn_handle, &mut rigid_body_set);
+
+        let mut level = Level::new(rigid_body_set, collider_set, vec![pawn_handle]);
+        level.set_render_hints(ground_collider, "ground", "green");
+        level.set_render_hints(pawn_collider, "pawn", "blue");
+        level
+    }
+}
+
+fn spatial_data(handle: RigidBodyHandle, body: &RigidBody, grounded: bool) -> SpatialData {
+    let trans = body.translation();
+    let rot = body.rotation();
+    let vector = |v: &Vector3<f32>| Coordinates {
+        x: v.x,
+        y: v.y,
+        z: v.z,
+    };
+
+    let coor = Coordinates {
+        x: trans.x,
+        y: trans.y,
+        z: trans.z,
+    };
+
+    let orient = Orientation {
+        i: rot.i,
+        j: rot.j,
+        k: rot.k,
+        w: rot.w,
+    };
+
+    SpatialData {
+        id: entity_id(handle),
+        coordinates: Some(coor),
+        orientation: Some(orient),
+        grounded,
+        linvel: Some(vector(body.linvel())),
+        angvel: Some(vector(body.angvel())),
+        sleeping: body.is_sleeping(),
+        ..Default::default()
+    }
+}
 
-        (rigid_body_set, collider_set, pawn_handle)
+/// Sends to everyone subscribed to the channel. Sending only fails without
+/// subscribers, in which case nobody missed the message.
+pub fn publish<T>(channel: &broadcast::Sender<T>, message: impl Into<T>) {
+    if channel.send(message.into()).is_err() {
+        trace!(
+            message = std::any::type_name::<T>(),
+            "No subscribers, message dropped."
+        );
     }
 }
 
+fn server_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 pub struct SimulationContext {
     gravity: Vector3<f32>,
     integration_parameters: IntegrationParameters,
@@ -217,13 +572,33 @@ pub struct SimulationContext {
     multibody_joint_set: MultibodyJointSet,
     ccd_solver: CCDSolver,
     query_pipeline: QueryPipeline,
+    max_substeps: u32,
 }
 
 impl SimulationContext {
-    pub fn new(gravity: Vector3<f32>) -> Self {
-        Self {
+    /// Default gravity and substeps, stepping `dt` seconds at a time.
+    pub fn with_timestep(dt: f32) -> Result<Self> {
+        Self::new(vector![0., -9.81, 0.], dt, DEFAULT_MAX_SUBSTEPS)
+    }
+
+    /// Fails unless `dt` is a positive number of seconds, no shorter than
+    /// a nanosecond, and at least one substep is allowed.
+    pub fn new(gravity: Vector3<f32>, dt: f32, max_substeps: u32) -> Result<Self> {
+        // Shorter steps round to a zero `Duration`, which the physics
+        // interval and the accumulator cannot work with.
+        let step = time::Duration::try_from_secs_f32(dt).unwrap_or_default();
+        ensure!(
+            !step.is_zero(),
+            "Physics timestep must be at least a nanosecond, got {dt}"
+        );
+        ensure!(max_substeps > 0, "At least one substep is needed per tick");
+
+        Ok(Self {
             gravity,
-            integration_parameters: IntegrationParameters::default(),
+            integration_parameters: IntegrationParameters {
+                dt,
+                ..Default::default()
+            },
             island_manager: IslandManager::new(),
             broad_phase: DefaultBroadPhase::new(),
             narrow_phase: NarrowPhase::new(),
@@ -231,22 +606,302 @@ impl SimulationContext {
             multibody_joint_set: MultibodyJointSet::new(),
             ccd_solver: CCDSolver::new(),
             query_pipeline: QueryPipeline::new(),
-        }
+            max_substeps,
+        })
+    }
+
+    /// Accumulator stepping this context at its configured rate.
+    pub fn timestep(&self) -> FixedTimestep {
+        FixedTimestep::new(
+            time::Duration::from_secs_f32(self.integration_parameters.dt),
+            self.max_substeps,
+        )
+    }
+
+    /// Whether any collider of the body rests on a surface, based on the
+    /// contacts found by the last step.
+    pub fn is_grounded(&self, body: &RigidBody) -> bool {
+        body.colliders().iter().any(|handle| {
+            self.narrow_phase
+                .contact_pairs_with(*handle)
+                .filter(|pair| pair.has_any_active_contact)
+                .flat_map(|pair| {
+                    // Manifold normals point from collider1 to collider2.
+                    let sign = if pair.collider1 == *handle { -1. } else { 1. };
+                    pair.manifolds
+                        .iter()
+                        .filter(|m| !m.points.is_empty())
+                        .map(move |m| m.data.normal.y * sign)
+                })
+                .any(|up| up >= GROUND_NORMAL_MIN_Y)
+        })
     }
 }
 
 impl Default for SimulationContext {
     fn default() -> Self {
-        Self {
-            gravity: vector![0., -9.81, 0.],
-            integration_parameters: IntegrationParameters::default(),
-            island_manager: IslandManager::new(),
-            broad_phase: DefaultBroadPhase::new(),
-            narrow_phase: NarrowPhase::new(),
-            impulse_joint_set: ImpulseJointSet::new(),
-            multibody_joint_set: MultibodyJointSet::new(),
-            ccd_solver: CCDSolver::new(),
-            query_pipeline: QueryPipeline::new(),
+        Self::with_timestep(DEFAULT_DT).expect("Default timestep is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::updates::Instruction;
+
+    /// Holds the inputs in turn, each for `steps_each` physics steps, and
+    /// returns the top planar speed reached.
+    fn hold_in_turn(inputs: &[ControlInput], steps_each: usize) -> f32 {
+        let mut ctx = SimulationContext::default();
+        let mut level = Simulation::initialize_world();
+        let mut phys_pipeline = PhysicsPipeline::new();
+        let pawn_handle = level.get_pawn_handles()[0];
+
+        let mut top_speed: f32 = 0.;
+        for input in inputs.iter().cycle().take(600 / steps_each) {
+            for _ in 0..steps_each {
+                let body = &mut level.get_rigid_body_set_mut()[pawn_handle];
+                body.apply_held_input(input, ctx.integration_parameters.dt);
+                Simulation::step(&mut phys_pipeline, &mut level, &mut ctx);
+
+                let body = &level.get_rigid_body_set()[pawn_handle];
+                let spatial = spatial_data(pawn_handle, body, ctx.is_grounded(body));
+                let linvel = spatial.linvel.unwrap();
+                let angvel = spatial.angvel.unwrap();
+
+                let speed = linvel.x.hypot(linvel.z);
+                assert!(speed <= MAX_LINEAR_VEL + 1e-3, "{speed}");
+                assert!(angvel.y.abs() <= MAX_ANGULAR_VEL + 1e-3, "{}", angvel.y);
+                top_speed = top_speed.max(speed);
+            }
+        }
+
+        top_speed
+    }
+
+    #[test]
+    fn held_movement_stays_within_velocity_caps() {
+        let right = ControlInput::from(Instruction::Right);
+        let up = ControlInput::from(Instruction::Up);
+
+        let turning = right.combined(&Instruction::Ccw.into());
+        let top_speed = hold_in_turn(&[turning], 1);
+        assert!(top_speed > MAX_LINEAR_VEL * 0.9, "{top_speed}");
+
+        // Holding one direction and then adding another must not stack the
+        // speeds up.
+        let top_speed = hold_in_turn(&[right, right.combined(&up)], 300);
+        assert!(top_speed > MAX_LINEAR_VEL * 0.9, "{top_speed}");
+
+        let top_speed = hold_in_turn(&[right, up], 20);
+        assert!(top_speed > MAX_LINEAR_VEL * 0.9, "{top_speed}");
+    }
+
+    #[test]
+    fn pawn_is_grounded_once_it_lands() {
+        let mut ctx = SimulationContext::default();
+        let mut level = Simulation::initialize_world();
+        let mut phys_pipeline = PhysicsPipeline::new();
+        let pawn_handle = level.get_pawn_handles()[0];
+
+        Simulation::step(&mut phys_pipeline, &mut level, &mut ctx);
+        assert!(!ctx.is_grounded(&level.get_rigid_body_set()[pawn_handle]));
+
+        let mut steps = 0;
+        while !ctx.is_grounded(&level.get_rigid_body_set()[pawn_handle]) {
+            assert!(steps < 600, "the pawn never landed");
+            Simulation::step(&mut phys_pipeline, &mut level, &mut ctx);
+            steps += 1;
+        }
+
+        // Jumps only go through from the ground.
+        let jump = ControlInput::from(Instruction::Jump);
+        assert_eq!(jump.grounded(false).jump, 0.);
+        let body = &mut level.get_rigid_body_set_mut()[pawn_handle];
+        let falling = body.linvel().y;
+        body.apply_input(&jump.grounded(true));
+        assert!(body.linvel().y > falling, "{}", body.linvel().y);
+    }
+
+    fn simulation(control_mode: ControlMode) -> (Simulation, mpsc::Sender<InstructionUpdate>) {
+        let (ins_tx, ins_rx) = mpsc::channel(10);
+        let sim = Simulation::new(
+            broadcast::channel(10).0,
+            watch::channel(SimulationUpdate::default()).0,
+            watch::channel(WorldDescription::default()).0,
+            time::Duration::from_millis(33),
+            ins_rx,
+            time::Duration::from_millis(200),
+            control_mode,
+            roster::Roster::shared(time::Duration::from_secs(60)),
+        );
+        (sim, ins_tx)
+    }
+
+    fn update(instruction: Instruction, state: InputState, sequence: u32) -> InstructionUpdate {
+        InstructionUpdate {
+            input: Some(crate::updates::instruction_update::Input::Instruction(
+                instruction as i32,
+            )),
+            player_id: "a".into(),
+            state: state as i32,
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn updates_are_stamped_with_tick_and_time() {
+        let (mut sim, _ins_tx) = simulation(ControlMode::Cooperative(Default::default()));
+        let mut events = sim.channel.subscribe();
+        let level = Simulation::initialize_world();
+        let ctx = SimulationContext::default();
+
+        sim.tick = 42;
+        let before = server_time_ms();
+        sim.send_update(&level, &ctx, 3, false);
+
+        let Ok(SimulationEvent {
+            event: Some(Event::Snapshot(update)),
+        }) = events.try_recv()
+        else {
+            panic!("a snapshot should be published");
+        };
+        assert_eq!(update.tick, 42);
+        assert!(update.server_time_ms >= before);
+        assert_eq!(update.debug.unwrap().tick_overruns, 3);
+        assert_eq!(sim.snapshot_channel.borrow().tick, 42);
+    }
+
+    #[test]
+    fn snapshots_carry_every_dynamic_body() {
+        let (mut sim, _ins_tx) = simulation(ControlMode::Cooperative(Default::default()));
+        let level = Simulation::initialize_world();
+        let pawn_id = entity_id(level.get_pawn_handles()[0]);
+
+        for _ in 0..2 {
+            sim.send_update(&level, &SimulationContext::default(), 0, false);
+            let ids: Vec<u64> = sim
+                .snapshot_channel
+                .borrow()
+                .spatial_updates
+                .iter()
+                .map(|spatial| spatial.id)
+                .collect();
+            // The fixed ground is described once on subscribe, not updated.
+            assert_eq!(ids, vec![pawn_id]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn snapshots_go_out_at_their_own_rate() {
+        let (mut sim, _ins_tx) = simulation(ControlMode::Cooperative(Default::default()));
+        let mut events = sim.channel.subscribe();
+        let running = tokio::spawn(async move {
+            sim.run(&mut SimulationContext::default()).await.unwrap();
+        });
+
+        let mut ticks = vec![];
+        while ticks.len() < 11 {
+            if let Event::Snapshot(update) = events.recv().await.unwrap().event.unwrap() {
+                ticks.push(update.tick);
+            }
+        }
+        running.abort();
+
+        // 10 snapshots 33ms apart cover 330ms, close to 40 steps of 1/120s.
+        let steps = ticks[10] - ticks[0];
+        assert!((39..=40).contains(&steps), "{ticks:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simulations_run_without_subscribers() {
+        let (mut sim, _ins_tx) = simulation(ControlMode::Cooperative(Default::default()));
+        let snapshots = sim.snapshot_channel.subscribe();
+        let running = tokio::spawn(async move {
+            sim.run(&mut SimulationContext::default()).await.unwrap();
+        });
+
+        time::sleep(time::Duration::from_secs(1)).await;
+        assert!(
+            snapshots.borrow().tick >= 100,
+            "{}",
+            snapshots.borrow().tick
+        );
+        running.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn empty_rooms_pause_until_a_player_connects() {
+        let (mut sim, _ins_tx) = simulation(ControlMode::Cooperative(Default::default()));
+        sim.set_pause_when_empty(true);
+        let roster = sim.roster.clone();
+        let snapshots = sim.snapshot_channel.subscribe();
+        let running = tokio::spawn(async move {
+            sim.run(&mut SimulationContext::default()).await.unwrap();
+        });
+
+        time::sleep(time::Duration::from_secs(1)).await;
+        assert_eq!(snapshots.borrow().tick, 0);
+
+        roster.lock().await.join("a");
+        let _connection = roster::Connection::open(&roster, "a").await.unwrap();
+        time::sleep(time::Duration::from_secs(1)).await;
+        assert!(snapshots.borrow().tick > 0);
+        running.abort();
+    }
+
+    #[tokio::test]
+    async fn taps_are_acked_once_applied() {
+        let (mut sim, ins_tx) = simulation(ControlMode::Cooperative(Default::default()));
+        let mut body = RigidBodyBuilder::dynamic().build();
+
+        ins_tx
+            .send(update(Instruction::Up, InputState::Press, 1))
+            .await
+            .unwrap();
+        sim.receive_instructions(&mut body, true);
+        assert_eq!(sim.acks.get("a"), Some(&1));
+
+        // The press after the tap is applied already, but acking it would
+        // claim the tap was too.
+        ins_tx
+            .send(update(Instruction::Jump, InputState::Tap, 2))
+            .await
+            .unwrap();
+        ins_tx
+            .send(update(Instruction::Up, InputState::Release, 3))
+            .await
+            .unwrap();
+        sim.receive_instructions(&mut body, true);
+        assert_eq!(sim.acks.get("a"), Some(&1));
+
+        sim.apply_instructions(&mut body, true).await;
+        assert_eq!(sim.acks.get("a"), Some(&3));
+    }
+
+    #[tokio::test]
+    async fn split_taps_are_acked_right_away() {
+        let split = split_control::SplitControl::shared(time::Duration::from_secs(60));
+        let (mut sim, ins_tx) = simulation(ControlMode::Split(split));
+        let mut body = RigidBodyBuilder::dynamic().build();
+
+        ins_tx
+            .send(update(Instruction::Jump, InputState::Tap, 4))
+            .await
+            .unwrap();
+        sim.receive_instructions(&mut body, true);
+        assert_eq!(sim.acks.get("a"), Some(&4));
+    }
+
+    #[test]
+    fn invalid_timesteps_are_rejected() {
+        let gravity = vector![0., -9.81, 0.];
+
+        for dt in [0., -DEFAULT_DT, 1e-12, f32::MAX, f32::NAN, f32::INFINITY] {
+            assert!(SimulationContext::new(gravity, dt, 1).is_err(), "{dt}");
         }
+        assert!(SimulationContext::new(gravity, DEFAULT_DT, 0).is_err());
+        assert!(SimulationContext::new(gravity, DEFAULT_DT, 1).is_ok());
     }
 }
@@ -0,0 +1,262 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::anyhow;
+
+use rapier3d::prelude::nalgebra::Vector2;
+
+use super::instruction::ControlInput;
+
+/// How instructions from several players sharing one pawn are combined.
+#[derive(Debug, Clone, Default)]
+pub enum AggregationPolicy {
+    /// Each axis moves in the direction most players asked for, ties cancel
+    /// out. Jumping needs more than half of the active players.
+    MajorityVote,
+    /// Mean of every active player's input.
+    #[default]
+    AveragedDirection,
+    /// Weighted mean of every active player's input. Players missing from the
+    /// map get a weight of 1.
+    Weighted(HashMap<String, f32>),
+}
+
+impl FromStr for AggregationPolicy {
+    type Err = anyhow::Error;
+
+    /// Parses `majority`, `average` or `weighted:<player>=<weight>,...`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "majority" => Ok(Self::MajorityVote),
+            None if s == "average" => Ok(Self::AveragedDirection),
+            Some(("weighted", weights)) => weights
+                .split(',')
+                .filter(|w| !w.is_empty())
+                .map(|w| {
+                    let (id, weight) = w
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("Expected <player>=<weight>, got {w}"))?;
+                    Ok((id.to_string(), weight.parse::<f32>()?))
+                })
+                .collect::<Result<_, Self::Err>>()
+                .map(Self::Weighted),
+            _ => Err(anyhow!("Unknown aggregation policy {s}")),
+        }
+    }
+}
+
+/// Collects instructions over one instruction window and resolves them into a
+/// single input for the shared pawn.
+#[derive(Debug, Default)]
+pub struct InstructionAggregator {
+    policy: AggregationPolicy,
+    inputs: HashMap<String, ControlInput>,
+}
+
+impl InstructionAggregator {
+    pub fn new(policy: AggregationPolicy) -> Self {
+        Self {
+            policy,
+            inputs: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, player_id: String, next: ControlInput) {
+        let input = self.inputs.entry(player_id).or_default();
+
+        // Repeated presses within a window do not give a player extra say.
+        *input = input.combined(&next);
+    }
+
+    /// Resolves and clears the current window. Returns `None` if nobody sent
+    /// anything or the players cancelled each other out.
+    pub fn resolve(&mut self) -> Option<ControlInput> {
+        if self.inputs.is_empty() {
+            return None;
+        }
+
+        let inputs = std::mem::take(&mut self.inputs);
+        self.blend(&inputs)
+    }
+
+    /// Combines one input per player according to the policy. Returns `None`
+    /// if the players cancelled each other out.
+    pub fn blend(&self, inputs: &HashMap<String, ControlInput>) -> Option<ControlInput> {
+        let resolved = match &self.policy {
+            AggregationPolicy::MajorityVote => majority_vote(inputs.values()),
+            AggregationPolicy::AveragedDirection => weighted_mean(inputs.values().map(|i| (i, 1.))),
+            AggregationPolicy::Weighted(weights) => weighted_mean(
+                inputs
+                    .iter()
+                    .map(|(id, i)| (i, weights.get(id).copied().unwrap_or(1.))),
+            ),
+        };
+
+        Some(resolved).filter(|input| !input.is_idle())
+    }
+}
+
+fn majority_vote<'a>(inputs: impl Iterator<Item = &'a ControlInput>) -> ControlInput {
+    let mut x = 0.;
+    let mut z = 0.;
+    let mut yaw = 0.;
+    let mut jumps = 0.;
+    let mut voters = 0.;
+
+    for input in inputs {
+        x += sign(input.direction.x);
+        z += sign(input.direction.y);
+        yaw += sign(input.yaw);
+        if input.jump >= 0.5 {
+            jumps += 1.;
+        }
+        voters += 1.;
+    }
+
+    ControlInput {
+        direction: Vector2::new(sign(x), sign(z)),
+        yaw: sign(yaw),
+        jump: if jumps > voters / 2. { 1. } else { 0. },
+    }
+    .clamped()
+}
+
+/// Like `f32::signum` but zero stays zero, so abstaining is not a vote.
+fn sign(v: f32) -> f32 {
+    if v == 0. {
+        0.
+    } else {
+        v.signum()
+    }
+}
+
+fn weighted_mean<'a>(inputs: impl Iterator<Item = (&'a ControlInput, f32)>) -> ControlInput {
+    let mut sum = ControlInput::default();
+    let mut total_weight = 0.;
+
+    for (input, weight) in inputs {
+        let weight = weight.max(0.);
+        sum.direction += input.direction * weight;
+        sum.yaw += input.yaw * weight;
+        sum.jump += input.jump * weight;
+        total_weight += weight;
+    }
+
+    if total_weight == 0. {
+        return ControlInput::default();
+    }
+
+    ControlInput {
+        direction: sum.direction / total_weight,
+        yaw: sum.yaw / total_weight,
+        jump: sum.jump / total_weight,
+    }
+    .clamped()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::updates::Instruction;
+
+    fn inputs(players: &[(&str, Instruction)]) -> HashMap<String, ControlInput> {
+        let mut inputs: HashMap<String, ControlInput> = HashMap::new();
+        for (player_id, instruction) in players {
+            let input = inputs.entry(player_id.to_string()).or_default();
+            *input = input.combined(&(*instruction).into());
+        }
+        inputs
+    }
+
+    #[test]
+    fn majority_vote_follows_the_most_players() {
+        let aggregator = InstructionAggregator::new(AggregationPolicy::MajorityVote);
+
+        let input = aggregator
+            .blend(&inputs(&[
+                ("a", Instruction::Right),
+                ("b", Instruction::Right),
+                ("c", Instruction::Left),
+                ("a", Instruction::Jump),
+            ]))
+            .unwrap();
+        assert_eq!(input.direction, Vector2::new(1., 0.));
+        // One jump out of three players is not a majority.
+        assert_eq!(input.jump, 0.);
+
+        let input = aggregator
+            .blend(&inputs(&[
+                ("a", Instruction::Jump),
+                ("b", Instruction::Jump),
+            ]))
+            .unwrap();
+        assert_eq!(input.jump, 1.);
+    }
+
+    #[test]
+    fn ties_cancel_out() {
+        let players = inputs(&[("a", Instruction::Cw), ("b", Instruction::Ccw)]);
+
+        for policy in [
+            AggregationPolicy::MajorityVote,
+            AggregationPolicy::AveragedDirection,
+        ] {
+            assert_eq!(InstructionAggregator::new(policy).blend(&players), None);
+        }
+    }
+
+    #[test]
+    fn weights_scale_each_player() {
+        let players = inputs(&[
+            ("a", Instruction::Right),
+            ("b", Instruction::Left),
+            ("c", Instruction::Up),
+        ]);
+        let weights = HashMap::from([("a".to_string(), 3.), ("c".to_string(), 0.)]);
+
+        let input = InstructionAggregator::new(AggregationPolicy::Weighted(weights))
+            .blend(&players)
+            .unwrap();
+        // b has the default weight of 1, c does not count at all.
+        assert_eq!(input.direction, Vector2::new(0.5, 0.));
+
+        // Negative weights count as zero rather than reversing the input.
+        let weights = HashMap::from([("a".to_string(), -1.), ("b".to_string(), 0.)]);
+        let aggregator = InstructionAggregator::new(AggregationPolicy::Weighted(weights));
+        let players = inputs(&[("a", Instruction::Right), ("b", Instruction::Left)]);
+        assert_eq!(aggregator.blend(&players), None);
+    }
+
+    #[test]
+    fn resolve_clears_the_window() {
+        let mut aggregator = InstructionAggregator::default();
+        aggregator.push("a".to_string(), Instruction::Jump.into());
+        aggregator.push("a".to_string(), Instruction::Jump.into());
+
+        assert_eq!(aggregator.resolve().unwrap().jump, 1.);
+        assert_eq!(aggregator.resolve(), None);
+    }
+
+    #[test]
+    fn policies_parse() {
+        assert!(matches!(
+            "majority".parse(),
+            Ok(AggregationPolicy::MajorityVote)
+        ));
+        assert!(matches!(
+            "average".parse(),
+            Ok(AggregationPolicy::AveragedDirection)
+        ));
+
+        let Ok(AggregationPolicy::Weighted(weights)) = "weighted:a=2,b=0.5,".parse() else {
+            panic!("weighted policy should parse");
+        };
+        assert_eq!(
+            weights,
+            HashMap::from([("a".into(), 2.), ("b".into(), 0.5)])
+        );
+
+        for invalid in ["", "median", "weighted:a", "weighted:a=x", "majority:a=1"] {
+            assert!(invalid.parse::<AggregationPolicy>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use rapier3d::prelude::*;
+
+// smallest change worth sending
+const POSITION_EPSILON: f32 = 1e-3;
+const ROTATION_EPSILON: f32 = 1e-3;
+
+#[derive(Debug)]
+struct SentState {
+    position: Isometry<f32>,
+    grounded: bool,
+    sleeping: bool,
+}
+
+/// Decides which bodies go into a snapshot. Between keyframes only bodies that
+/// are awake and moved noticeably since they were last sent are included;
+/// every `keyframe_interval` snapshots everything is sent so receivers that
+/// missed deltas catch up.
+#[derive(Debug)]
+pub struct DeltaEncoder {
+    keyframe_interval: u64,
+    snapshots: u64,
+    last_sent: HashMap<u64, SentState>,
+}
+
+impl DeltaEncoder {
+    pub fn new(keyframe_interval: u64) -> Self {
+        Self {
+            keyframe_interval: keyframe_interval.max(1),
+            snapshots: 0,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Starts a new snapshot and returns whether it is a keyframe.
+    pub fn begin(&mut self) -> bool {
+        let keyframe = self.snapshots.is_multiple_of(self.keyframe_interval);
+        self.snapshots += 1;
+
+        if keyframe {
+            // Forget despawned bodies, everything present gets recorded again.
+            self.last_sent.clear();
+        }
+
+        keyframe
+    }
+
+    /// Whether the body belongs in the current snapshot, recording it as sent
+    /// if so.
+    pub fn should_send(
+        &mut self,
+        keyframe: bool,
+        id: u64,
+        body: &RigidBody,
+        grounded: bool,
+    ) -> bool {
+        let changed = match self.last_sent.get(&id) {
+            _ if keyframe => true,
+            None => true,
+            // Falling asleep is sent once so receivers stop extrapolating.
+            Some(sent) if sent.sleeping != body.is_sleeping() => true,
+            Some(_) if body.is_sleeping() => false,
+            Some(sent) => {
+                sent.grounded != grounded
+                    || (sent.position.translation.vector - body.translation()).norm()
+                        > POSITION_EPSILON
+                    || sent.position.rotation.angle_to(body.rotation()) > ROTATION_EPSILON
+            }
+        };
+
+        if changed {
+            self.last_sent.insert(
+                id,
+                SentState {
+                    position: *body.position(),
+                    grounded,
+                    sleeping: body.is_sleeping(),
+                },
+            );
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body_at(x: f32) -> RigidBody {
+        RigidBodyBuilder::dynamic()
+            .translation(vector![x, 0., 0.])
+            .build()
+    }
+
+    #[test]
+    fn only_noticeable_changes_are_sent() {
+        let mut encoder = DeltaEncoder::new(10);
+        let mut body = body_at(0.);
+
+        assert!(encoder.begin());
+        assert!(encoder.should_send(true, 1, &body, false));
+
+        assert!(!encoder.begin());
+        body.set_translation(vector![POSITION_EPSILON / 2., 0., 0.], false);
+        assert!(!encoder.should_send(false, 1, &body, false));
+
+        // Small moves add up against the last position sent.
+        body.set_translation(vector![POSITION_EPSILON * 2., 0., 0.], false);
+        assert!(encoder.should_send(false, 1, &body, false));
+        assert!(!encoder.should_send(false, 1, &body, false));
+
+        body.set_rotation(Rotation::new(vector![0., ROTATION_EPSILON * 2., 0.]), false);
+        assert!(encoder.should_send(false, 1, &body, false));
+
+        assert!(encoder.should_send(false, 1, &body, true));
+
+        // Bodies never sent before always are.
+        assert!(encoder.should_send(false, 2, &body_at(5.), false));
+    }
+
+    #[test]
+    fn falling_asleep_is_sent_once() {
+        let mut encoder = DeltaEncoder::new(10);
+        let mut body = body_at(0.);
+        encoder.begin();
+        encoder.should_send(true, 1, &body, false);
+
+        body.sleep();
+        assert!(encoder.should_send(false, 1, &body, false));
+        assert!(!encoder.should_send(false, 1, &body, false));
+
+        // Sleeping bodies stay quiet even if moved, until they wake up.
+        body.set_translation(vector![1., 0., 0.], false);
+        assert!(!encoder.should_send(false, 1, &body, false));
+        body.wake_up(true);
+        assert!(encoder.should_send(false, 1, &body, false));
+    }
+
+    #[test]
+    fn keyframes_send_everything_at_the_interval() {
+        let mut encoder = DeltaEncoder::new(3);
+        let mut body = body_at(0.);
+        body.sleep();
+
+        let keyframes: Vec<bool> = (0..7).map(|_| encoder.begin()).collect();
+        assert_eq!(
+            keyframes,
+            vec![true, false, false, true, false, false, true]
+        );
+
+        encoder.should_send(true, 1, &body, false);
+        assert!(!encoder.should_send(false, 1, &body, false));
+        assert!(encoder.should_send(true, 1, &body, false));
+
+        // An interval of zero would never produce a keyframe.
+        let mut encoder = DeltaEncoder::new(0);
+        assert!(encoder.begin() && encoder.begin());
+    }
+}
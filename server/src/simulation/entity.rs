@@ -0,0 +1,35 @@
+use rapier3d::prelude::RigidBodyHandle;
+
+/// Id a body is known by on the wire. Packs the handle's generation above its
+/// index, so ids of removed bodies are never handed out again even though
+/// rapier reuses the slot.
+pub fn entity_id(handle: RigidBodyHandle) -> u64 {
+    let (index, generation) = handle.into_raw_parts();
+    ((generation as u64) << 32) | index as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rapier3d::prelude::{RigidBodyBuilder, RigidBodySet};
+
+    #[test]
+    fn reused_slots_get_new_ids() {
+        let mut bodies = RigidBodySet::new();
+        let first = bodies.insert(RigidBodyBuilder::dynamic().build());
+        let other = bodies.insert(RigidBodyBuilder::dynamic().build());
+        assert_ne!(entity_id(first), entity_id(other));
+
+        bodies.remove(
+            first,
+            &mut Default::default(),
+            &mut Default::default(),
+            &mut Default::default(),
+            &mut Default::default(),
+            true,
+        );
+        let reused = bodies.insert(RigidBodyBuilder::dynamic().build());
+        assert_eq!(reused.into_raw_parts().0, first.into_raw_parts().0);
+        assert_ne!(entity_id(reused), entity_id(first));
+    }
+}
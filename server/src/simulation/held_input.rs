@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use super::instruction::ControlInput;
+use crate::updates::{instruction_update::Input, Instruction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum HeldKey {
+    Instruction(Instruction),
+    Analog,
+}
+
+impl From<&Input> for HeldKey {
+    fn from(input: &Input) -> Self {
+        match input {
+            Input::Instruction(ins) => {
+                Self::Instruction(Instruction::try_from(*ins).unwrap_or_default())
+            }
+            Input::Analog(_) => Self::Analog,
+        }
+    }
+}
+
+/// Inputs players are currently holding down, applied every physics step
+/// until released. A held analog stick is replaced by each new press.
+#[derive(Debug, Default)]
+pub struct HeldInputs {
+    players: HashMap<String, HashMap<HeldKey, ControlInput>>,
+}
+
+impl HeldInputs {
+    pub fn press(&mut self, player_id: String, input: &Input, control: ControlInput) {
+        self.players
+            .entry(player_id)
+            .or_default()
+            .insert(input.into(), control);
+    }
+
+    pub fn release(&mut self, player_id: &str, input: &Input) {
+        if let Some(held) = self.players.get_mut(player_id) {
+            held.remove(&input.into());
+
+            if held.is_empty() {
+                self.players.remove(player_id);
+            }
+        }
+    }
+
+    /// Keeps only the held inputs for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&str, &ControlInput) -> bool) {
+        self.players.retain(|player_id, held| {
+            held.retain(|_, input| keep(player_id, input));
+            !held.is_empty()
+        });
+    }
+
+    /// Everything each player is holding, combined into one input per player.
+    pub fn per_player(&self) -> HashMap<String, ControlInput> {
+        self.players
+            .iter()
+            .map(|(player_id, held)| {
+                let input = held
+                    .values()
+                    .fold(ControlInput::default(), |acc, input| acc.combined(input));
+                (player_id.clone(), input)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::updates::AnalogInput;
+
+    fn instruction(ins: Instruction) -> Input {
+        Input::Instruction(ins as i32)
+    }
+
+    fn analog(x: f32) -> Input {
+        Input::Analog(AnalogInput {
+            x,
+            magnitude: 1.,
+            ..Default::default()
+        })
+    }
+
+    fn press(held: &mut HeldInputs, player_id: &str, input: Input) {
+        let control = match &input {
+            Input::Instruction(ins) => Instruction::try_from(*ins).unwrap().into(),
+            Input::Analog(analog) => ControlInput::try_from(analog).unwrap(),
+        };
+        held.press(player_id.into(), &input, control);
+    }
+
+    #[test]
+    fn inputs_are_held_until_released() {
+        let mut held = HeldInputs::default();
+        press(&mut held, "a", instruction(Instruction::Right));
+        press(&mut held, "a", instruction(Instruction::Jump));
+
+        let input = held.per_player()["a"];
+        assert_eq!(input.direction.x, 1.);
+        assert_eq!(input.jump, 1.);
+
+        held.release("a", &instruction(Instruction::Jump));
+        assert_eq!(held.per_player()["a"].jump, 0.);
+
+        held.release("a", &instruction(Instruction::Right));
+        assert!(held.per_player().is_empty());
+    }
+
+    #[test]
+    fn analog_presses_replace_each_other() {
+        let mut held = HeldInputs::default();
+        press(&mut held, "a", analog(1.));
+        press(&mut held, "a", analog(-1.));
+        assert_eq!(held.per_player()["a"].direction.x, -1.);
+
+        // Any analog release lets go of the stick.
+        held.release("a", &analog(0.));
+        assert!(held.per_player().is_empty());
+    }
+
+    #[test]
+    fn retain_drops_players_left_without_inputs() {
+        let mut held = HeldInputs::default();
+        press(&mut held, "a", instruction(Instruction::Left));
+        press(&mut held, "b", instruction(Instruction::Up));
+
+        held.retain(|player_id, _| player_id == "b");
+        assert_eq!(held.per_player().len(), 1);
+        assert!(held.per_player().contains_key("b"));
+    }
+}
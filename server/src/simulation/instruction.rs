@@ -1,48 +1,321 @@
-use crate::updates::Instruction;
+use std::f32::consts::PI;
+
+use anyhow::{anyhow, Result};
+
+use nalgebra::{vector, Vector2, Vector3};
+use rapier3d::prelude::*;
+
+use super::{MAX_ANGULAR_VEL, MAX_LINEAR_VEL};
+use crate::updates::{instruction_update::Input, AnalogInput, Instruction, InstructionUpdate};
+
+// velocity change requested by a single instruction
+const LINEAR_VEL_STEP: f32 = 2.;
+const ANGULAR_VEL_STEP: f32 = PI / 4.;
+const JUMP_VEL: f32 = 7.;
+
+// acceleration while an input is held down
+const LINEAR_ACCEL: f32 = 20.;
+const ANGULAR_ACCEL: f32 = 2. * PI;
+
+/// Normalised control request for a pawn. Discrete instructions map onto unit
+/// values, blended inputs from several players land anywhere in between.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ControlInput {
+    /// Planar movement on the x/z plane, magnitude at most 1.
+    pub direction: Vector2<f32>,
+    /// Rotation about y in [-1, 1], positive is counter-clockwise.
+    pub yaw: f32,
+    /// Jump request in [0, 1], a jump is performed from 0.5 upwards.
+    pub jump: f32,
+}
+
+impl ControlInput {
+    /// Clamps every component back into its valid range.
+    pub fn clamped(mut self) -> Self {
+        if self.direction.norm() > 1. {
+            self.direction = self.direction.normalize();
+        }
+        self.yaw = self.yaw.clamp(-1., 1.);
+        self.jump = self.jump.clamp(0., 1.);
+        self
+    }
+
+    /// Adds another input on top of this one, keeping the result in range.
+    pub fn combined(self, other: &ControlInput) -> Self {
+        Self {
+            direction: self.direction + other.direction,
+            yaw: self.yaw + other.yaw,
+            jump: self.jump + other.jump,
+        }
+        .clamped()
+    }
+
+    /// Drops the jump request unless the body has something to jump off.
+    pub fn grounded(mut self, grounded: bool) -> Self {
+        if !grounded {
+            self.jump = 0.;
+        }
+        self
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.direction == Vector2::zeros() && self.yaw == 0. && self.jump == 0.
+    }
+
+    /// The discrete instructions covering what this input asks for.
+    pub fn instructions(&self) -> Vec<Instruction> {
+        let mut instructions = vec![];
+
+        if self.direction.x != 0. {
+            instructions.push(if self.direction.x < 0. {
+                Instruction::Left
+            } else {
+                Instruction::Right
+            });
+        }
+        if self.direction.y != 0. {
+            instructions.push(if self.direction.y < 0. {
+                Instruction::Up
+            } else {
+                Instruction::Down
+            });
+        }
+        if self.yaw != 0. {
+            instructions.push(if self.yaw < 0. {
+                Instruction::Cw
+            } else {
+                Instruction::Ccw
+            });
+        }
+        if self.jump != 0. {
+            instructions.push(Instruction::Jump);
+        }
+
+        instructions
+    }
+}
+
+impl TryFrom<&InstructionUpdate> for ControlInput {
+    type Error = anyhow::Error;
+
+    fn try_from(update: &InstructionUpdate) -> Result<Self> {
+        match &update.input {
+            Some(Input::Instruction(ins)) => Instruction::try_from(*ins)
+                .map(Self::from)
+                .map_err(|_| anyhow!("Unknown instruction {ins}")),
+            Some(Input::Analog(analog)) => Self::try_from(analog),
+            None => Err(anyhow!("Instruction update without input")),
+        }
+    }
+}
+
+impl TryFrom<&AnalogInput> for ControlInput {
+    type Error = anyhow::Error;
+
+    fn try_from(analog: &AnalogInput) -> Result<Self> {
+        let yaw = analog.yaw.unwrap_or(0.);
+        if ![analog.x, analog.z, analog.magnitude, yaw]
+            .iter()
+            .all(|v| v.is_finite())
+        {
+            return Err(anyhow!("Analog input must be finite"));
+        }
+
+        let direction = vector![analog.x, analog.z];
+        let direction = if direction.norm() > 0. {
+            direction.normalize() * analog.magnitude.clamp(0., 1.)
+        } else {
+            direction
+        };
+
+        Ok(Self {
+            direction,
+            yaw,
+            jump: 0.,
+        }
+        .clamped())
+    }
+}
+
+impl From<Instruction> for ControlInput {
+    fn from(instruction: Instruction) -> Self {
+        // Client convention: "up" moves away from the camera along -z.
+        let (direction, yaw, jump) = match instruction {
+            Instruction::Up => (vector![0., -1.], 0., 0.),
+            Instruction::Down => (vector![0., 1.], 0., 0.),
+            Instruction::Left => (vector![-1., 0.], 0., 0.),
+            Instruction::Right => (vector![1., 0.], 0., 0.),
+            Instruction::Jump => (Vector2::zeros(), 0., 1.),
+            Instruction::Cw => (Vector2::zeros(), -1., 0.),
+            Instruction::Ccw => (Vector2::zeros(), 1., 0.),
+        };
+
+        Self {
+            direction,
+            yaw,
+            jump,
+        }
+    }
+}
 
 pub trait InstructionHandler {
-    fn apply_instruction(&mut self, instruction: Instruction);
-}
-
-/*
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[repr(u8)]
-pub enum Instruction {
-    Jump,
-    Left,
-    Right,
-    Up,
-    Down,
-    Cw,
-    Ccw,
-}
-
-impl Instruction {
-    fn apply(&self, rb: &mut RigidBody) {
-        let lin_vel = rb.linvel().norm();
-        let ang_vel = rb.angvel().norm().real();
-
-        match self {
-            Instruction::Up => {
-                let diff = MAX_LINEAR_VEL - lin_vel;
-
-                if diff >= 0. {
-                    rb.apply_impulse(vector![0., 0., diff.min(1.)], true);
-                }
-            }
-            Instruction::Down => {
-                let diff = -MAX_LINEAR_VEL - lin_vel;
-
-                if diff >= 0. {
-                    rb.apply_impulse(vector![0., 0., diff.min(-1.)], true);
-                }
-            }
-            Instruction::Jump => todo!(),
-            Instruction::Left => todo!(),
-            Instruction::Right => todo!(),
-            Instruction::Cw => todo!(),
-            Instruction::Ccw => todo!(),
-        }
-    }
-}
-*/
+    /// Applies a one-off input such as a single key press.
+    fn apply_input(&mut self, input: &ControlInput);
+
+    /// Applies a held input for one physics step of `dt` seconds.
+    fn apply_held_input(&mut self, input: &ControlInput, dt: f32);
+}
+
+impl InstructionHandler for RigidBody {
+    fn apply_input(&mut self, input: &ControlInput) {
+        push_input(self, input, LINEAR_VEL_STEP, ANGULAR_VEL_STEP);
+    }
+
+    fn apply_held_input(&mut self, input: &ControlInput, dt: f32) {
+        push_input(self, input, LINEAR_ACCEL * dt, ANGULAR_ACCEL * dt);
+    }
+}
+
+fn push_input(rb: &mut RigidBody, input: &ControlInput, linear_step: f32, angular_step: f32) {
+    let input = input.clamped();
+
+    let magnitude = input.direction.norm();
+    if magnitude > 0. {
+        let dir = input.direction / magnitude;
+        push_linear(rb, vector![dir.x, 0., dir.y], linear_step * magnitude);
+    }
+
+    if input.yaw != 0. {
+        push_angular(rb, input.yaw.signum(), angular_step * input.yaw.abs());
+    }
+
+    if input.jump >= 0.5 {
+        let diff = capped_diff(rb.linvel().y, JUMP_VEL, JUMP_VEL);
+        rb.apply_impulse(vector![0., rb.mass() * diff, 0.], true);
+    }
+}
+
+/// Velocity change along an axis, limited to `step` and never taking the
+/// velocity past `max`.
+fn capped_diff(current: f32, step: f32, max: f32) -> f32 {
+    (max - current).clamp(0., step)
+}
+
+/// Pushes the body along `dir` on the x/z plane. The planar speed is capped
+/// as a whole, so combining or alternating directions cannot add up past
+/// `MAX_LINEAR_VEL`. A body already moving faster can still be steered or
+/// slowed down, just not sped up.
+fn push_linear(rb: &mut RigidBody, dir: Vector3<f32>, step: f32) {
+    let linvel = rb.linvel();
+    let planar = vector![linvel.x, 0., linvel.z];
+    let cap = planar.norm().max(MAX_LINEAR_VEL);
+    let diff = (planar + dir * step).cap_magnitude(cap) - planar;
+
+    if diff.norm() > 0. {
+        rb.apply_impulse(diff * rb.mass(), true);
+    }
+}
+
+fn push_angular(rb: &mut RigidBody, sign: f32, step: f32) {
+    let current = rb.angvel().y * sign;
+    let diff = capped_diff(current, step, MAX_ANGULAR_VEL);
+
+    if diff > 0. {
+        let inertia = rb.mass_properties().local_mprops.principal_inertia().y;
+        rb.apply_torque_impulse(vector![0., sign * inertia * diff, 0.], true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pawn() -> (RigidBodySet, RigidBodyHandle) {
+        let mut bodies = RigidBodySet::new();
+        let mut colliders = ColliderSet::new();
+        let handle = bodies.insert(RigidBodyBuilder::dynamic().build());
+        let collider = ColliderBuilder::cuboid(1., 1., 1.).mass(2.).build();
+        colliders.insert_with_parent(collider, handle, &mut bodies);
+        (bodies, handle)
+    }
+
+    fn planar_speed(rb: &RigidBody) -> f32 {
+        rb.linvel().x.hypot(rb.linvel().z)
+    }
+
+    #[test]
+    fn instructions_push_the_pawn() {
+        let (mut bodies, handle) = pawn();
+        let rb = &mut bodies[handle];
+
+        rb.apply_input(&Instruction::Right.into());
+        rb.apply_input(&Instruction::Up.into());
+        rb.apply_input(&Instruction::Ccw.into());
+        rb.apply_input(&Instruction::Jump.into());
+
+        let linvel = rb.linvel();
+        assert!((linvel.x - LINEAR_VEL_STEP).abs() < 1e-4, "{linvel}");
+        assert!((linvel.z + LINEAR_VEL_STEP).abs() < 1e-4, "{linvel}");
+        assert!((linvel.y - JUMP_VEL).abs() < 1e-4, "{linvel}");
+        assert!((rb.angvel().y - ANGULAR_VEL_STEP).abs() < 1e-4);
+    }
+
+    #[test]
+    fn planar_speed_is_capped_across_directions() {
+        let (mut bodies, handle) = pawn();
+        let rb = &mut bodies[handle];
+
+        let diagonal = ControlInput::from(Instruction::Right).combined(&Instruction::Up.into());
+        for input in [Instruction::Right.into(), diagonal]
+            .iter()
+            .cycle()
+            .take(20)
+        {
+            rb.apply_input(input);
+            assert!(planar_speed(rb) <= MAX_LINEAR_VEL + 1e-3, "{}", rb.linvel());
+        }
+        for instruction in [Instruction::Right, Instruction::Up]
+            .iter()
+            .cycle()
+            .take(20)
+        {
+            rb.apply_input(&(*instruction).into());
+            assert!(planar_speed(rb) <= MAX_LINEAR_VEL + 1e-3, "{}", rb.linvel());
+        }
+        assert!(planar_speed(rb) > MAX_LINEAR_VEL - 1e-3);
+
+        // Pushing back still slows the body down.
+        rb.apply_input(&Instruction::Left.into());
+        assert!(planar_speed(rb) < MAX_LINEAR_VEL - 1.);
+    }
+
+    fn analog(x: f32, z: f32, magnitude: f32, yaw: Option<f32>) -> Result<ControlInput> {
+        ControlInput::try_from(&AnalogInput {
+            x,
+            z,
+            magnitude,
+            yaw,
+        })
+    }
+
+    #[test]
+    fn analog_input_is_normalised() {
+        let input = analog(3., 4., 0.5, None).unwrap();
+        assert!((input.direction - vector![0.3, 0.4]).norm() < 1e-6);
+        assert_eq!(input.yaw, 0.);
+        assert_eq!(input.jump, 0.);
+
+        let input = analog(0., -2., 7., Some(-3.)).unwrap();
+        assert!((input.direction - vector![0., -1.]).norm() < 1e-6);
+        assert_eq!(input.yaw, -1.);
+
+        assert!(analog(0., 0., 1., None).unwrap().is_idle());
+    }
+
+    #[test]
+    fn non_finite_analog_input_is_rejected() {
+        assert!(analog(f32::NAN, 0., 1., None).is_err());
+        assert!(analog(1., 0., f32::INFINITY, None).is_err());
+        assert!(analog(1., 0., 1., Some(f32::NAN)).is_err());
+    }
+}
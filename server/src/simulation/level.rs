@@ -1,11 +1,26 @@
-use rapier3d::prelude::{ColliderSet, RigidBodyHandle, RigidBodySet};
+use std::collections::HashMap;
+
+use rapier3d::prelude::{
+    Aabb, BoundingVolume, ColliderHandle, ColliderSet, Isometry, Point, RigidBodyHandle,
+    RigidBodySet, TypedShape,
+};
+
+use super::entity::entity_id;
+use crate::updates::{
+    ColliderDescription, Coordinates, Orientation, RenderHints, ShapeType, WorldDescription,
+};
 
 pub mod level_one;
 
+// room left around the initial colliders for bodies to move in
+const BOUNDS_MARGIN: f32 = 16.;
+
 pub struct Level {
     rigid_body_set: RigidBodySet,
     collider_set: ColliderSet,
     pawn_handles: Vec<RigidBodyHandle>,
+    render_hints: HashMap<ColliderHandle, RenderHints>,
+    bounds: Aabb,
 }
 
 impl Level {
@@ -14,10 +29,19 @@ impl Level {
         collider_set: ColliderSet,
         pawn_handles: Vec<RigidBodyHandle>,
     ) -> Self {
+        let bounds = collider_set
+            .iter()
+            .map(|(_, collider)| collider.compute_aabb())
+            .reduce(|a, b| a.merged(&b))
+            .unwrap_or_else(Aabb::new_invalid)
+            .loosened(BOUNDS_MARGIN);
+
         Self {
             rigid_body_set,
             collider_set,
             pawn_handles,
+            render_hints: HashMap::new(),
+            bounds,
         }
     }
 
@@ -37,6 +61,11 @@ impl Level {
         &mut self.collider_set
     }
 
+    /// Both sets at once, as needed to step the physics pipeline.
+    pub fn get_sets_mut(&mut self) -> (&mut RigidBodySet, &mut ColliderSet) {
+        (&mut self.rigid_body_set, &mut self.collider_set)
+    }
+
     pub fn get_pawn_handles(&self) -> &Vec<RigidBodyHandle> {
         &self.pawn_handles
     }
@@ -44,4 +73,132 @@ impl Level {
     pub fn get_pawn_handles_mut(&mut self) -> &mut Vec<RigidBodyHandle> {
         &mut self.pawn_handles
     }
+
+    pub fn set_render_hints(&mut self, handle: ColliderHandle, name: &str, color: &str) {
+        self.render_hints.insert(
+            handle,
+            RenderHints {
+                name: name.to_string(),
+                color: color.to_string(),
+            },
+        );
+    }
+
+    /// Describes every collider so clients can build the scene. Colliders on
+    /// fixed bodies are given in world space as they never move.
+    pub fn describe(&self) -> WorldDescription {
+        let colliders = self
+            .collider_set
+            .iter()
+            .map(|(handle, collider)| {
+                let parent = collider
+                    .parent()
+                    .filter(|p| !self.rigid_body_set[*p].is_fixed());
+                let position = match (parent, collider.position_wrt_parent()) {
+                    (Some(_), Some(relative)) => relative,
+                    _ => collider.position(),
+                };
+                let (shape, half_extents) = describe_shape(collider.shape().as_typed_shape());
+
+                ColliderDescription {
+                    shape: shape as i32,
+                    half_extents: Some(half_extents),
+                    parent: parent.map(entity_id),
+                    coordinates: Some(coordinates(position)),
+                    orientation: Some(orientation(position)),
+                    hints: self.render_hints.get(&handle).cloned(),
+                }
+            })
+            .collect();
+
+        WorldDescription {
+            colliders,
+            bounds_min: Some(point_coordinates(&self.bounds.mins)),
+            bounds_max: Some(point_coordinates(&self.bounds.maxs)),
+        }
+    }
+}
+
+fn describe_shape(shape: TypedShape) -> (ShapeType, Coordinates) {
+    let (shape, [x, y, z]) = match shape {
+        TypedShape::Cuboid(c) => (ShapeType::Cuboid, c.half_extents.into()),
+        TypedShape::Ball(b) => (ShapeType::Ball, [b.radius; 3]),
+        TypedShape::Capsule(c) => (ShapeType::Capsule, [c.radius, c.half_height(), c.radius]),
+        TypedShape::Cylinder(c) => (ShapeType::Cylinder, [c.radius, c.half_height, c.radius]),
+        _ => (ShapeType::UnsupportedShape, [0.; 3]),
+    };
+
+    (shape, Coordinates { x, y, z })
+}
+
+fn coordinates(position: &Isometry<f32>) -> Coordinates {
+    let trans = position.translation;
+    Coordinates {
+        x: trans.x,
+        y: trans.y,
+        z: trans.z,
+    }
+}
+
+fn point_coordinates(point: &Point<f32>) -> Coordinates {
+    Coordinates {
+        x: point.x,
+        y: point.y,
+        z: point.z,
+    }
+}
+
+fn orientation(position: &Isometry<f32>) -> Orientation {
+    let rot = position.rotation;
+    Orientation {
+        i: rot.i,
+        j: rot.j,
+        k: rot.k,
+        w: rot.w,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rapier3d::prelude::{nalgebra, vector, ColliderBuilder, RigidBodyBuilder};
+
+    #[test]
+    fn colliders_are_described_in_their_frame() {
+        let mut bodies = RigidBodySet::new();
+        let mut colliders = ColliderSet::new();
+
+        let ground = bodies.insert(RigidBodyBuilder::fixed().translation(vector![0., -1., 0.]));
+        let ground_collider = colliders.insert_with_parent(
+            ColliderBuilder::cuboid(10., 1., 10.),
+            ground,
+            &mut bodies,
+        );
+        let ball = bodies.insert(RigidBodyBuilder::dynamic().translation(vector![0., 5., 0.]));
+        colliders.insert_with_parent(
+            ColliderBuilder::ball(0.5).translation(vector![0., 1., 0.]),
+            ball,
+            &mut bodies,
+        );
+
+        let mut level = Level::new(bodies, colliders, vec![ball]);
+        level.set_render_hints(ground_collider, "ground", "green");
+        let world = level.describe();
+
+        let ground = world.colliders.iter().find(|c| c.parent.is_none()).unwrap();
+        assert_eq!(ground.shape(), ShapeType::Cuboid);
+        assert_eq!(ground.half_extents.as_ref().unwrap().x, 10.);
+        assert_eq!(ground.coordinates.as_ref().unwrap().y, -1.);
+        assert_eq!(ground.hints.as_ref().unwrap().name, "ground");
+
+        // Colliders of moving bodies are relative to the body they follow.
+        let ball = world.colliders.iter().find(|c| c.parent.is_some()).unwrap();
+        assert_eq!(ball.shape(), ShapeType::Ball);
+        assert_eq!(ball.parent, Some(entity_id(level.get_pawn_handles()[0])));
+        assert_eq!(ball.coordinates.as_ref().unwrap().y, 1.);
+        assert!(ball.hints.is_none());
+
+        let (min, max) = (world.bounds_min.unwrap(), world.bounds_max.unwrap());
+        assert!(min.x <= -10. - BOUNDS_MARGIN && max.y >= 6.5 + BOUNDS_MARGIN);
+    }
 }
@@ -0,0 +1,216 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub type SharedRoster = Arc<Mutex<Roster>>;
+
+#[derive(Debug, Clone, Copy)]
+enum Presence {
+    /// Number of open subscriptions.
+    Connected(u32),
+    Away(Instant),
+}
+
+/// Players that joined a room and whether they are watching it. Players that
+/// go away keep their place for the grace period so they can resume.
+/// Everyone else watching the room is counted as a spectator.
+#[derive(Debug)]
+pub struct Roster {
+    grace_period: Duration,
+    players: HashMap<String, Presence>,
+    spectators: u32,
+}
+
+impl Roster {
+    pub fn new(grace_period: Duration) -> Self {
+        Self {
+            grace_period,
+            players: HashMap::new(),
+            spectators: 0,
+        }
+    }
+
+    pub fn shared(grace_period: Duration) -> SharedRoster {
+        Arc::new(Mutex::new(Self::new(grace_period)))
+    }
+
+    /// Adds the player, away until they subscribe. Returns whether they are new.
+    pub fn join(&mut self, player_id: &str) -> bool {
+        if self.players.contains_key(player_id) {
+            return false;
+        }
+
+        self.players
+            .insert(player_id.to_string(), Presence::Away(Instant::now()));
+        true
+    }
+
+    pub fn contains(&self, player_id: &str) -> bool {
+        self.players.contains_key(player_id)
+    }
+
+    /// Players in the room, connected or not.
+    pub fn player_count(&self) -> u32 {
+        self.players.len() as u32
+    }
+
+    /// Whether any player is subscribed right now.
+    pub fn any_connected(&self) -> bool {
+        self.players
+            .values()
+            .any(|presence| matches!(presence, Presence::Connected(_)))
+    }
+
+    pub fn spectators(&self) -> u32 {
+        self.spectators
+    }
+
+    /// Players currently within their grace period.
+    pub fn away(&self) -> impl Iterator<Item = &str> {
+        self.players
+            .iter()
+            .filter(|(_, presence)| matches!(presence, Presence::Away(_)))
+            .map(|(player_id, _)| player_id.as_str())
+    }
+
+    /// Removes players that have been away for longer than the grace period
+    /// and returns them.
+    pub fn expire_away(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired = vec![];
+
+        self.players.retain(|player_id, presence| match presence {
+            Presence::Away(since) if now.duration_since(*since) >= self.grace_period => {
+                expired.push(player_id.clone());
+                false
+            }
+            _ => true,
+        });
+
+        expired
+    }
+
+    fn connect(&mut self, player_id: &str) -> bool {
+        match self.players.get_mut(player_id) {
+            Some(Presence::Connected(count)) => *count += 1,
+            Some(presence) => *presence = Presence::Connected(1),
+            None => return false,
+        }
+        true
+    }
+
+    fn disconnect(&mut self, player_id: &str) {
+        if let Some(presence) = self.players.get_mut(player_id) {
+            *presence = match *presence {
+                Presence::Connected(count) if count > 1 => Presence::Connected(count - 1),
+                _ => Presence::Away(Instant::now()),
+            };
+        }
+    }
+}
+
+/// Keeps a player connected, or a spectator counted, for as long as it is
+/// alive, held by their subscription stream.
+#[derive(Debug)]
+pub struct Connection {
+    roster: SharedRoster,
+    /// `None` for spectators.
+    player_id: Option<String>,
+}
+
+impl Connection {
+    /// Fails if the player is not, or no longer, in the roster.
+    pub async fn open(roster: &SharedRoster, player_id: &str) -> Option<Self> {
+        roster.lock().await.connect(player_id).then(|| Self {
+            roster: roster.clone(),
+            player_id: Some(player_id.to_string()),
+        })
+    }
+
+    pub async fn spectate(roster: &SharedRoster) -> Self {
+        roster.lock().await.spectators += 1;
+        Self {
+            roster: roster.clone(),
+            player_id: None,
+        }
+    }
+
+    pub fn is_spectator(&self) -> bool {
+        self.player_id.is_none()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let roster = self.roster.clone();
+        let player_id = self.player_id.take();
+
+        // Streams are dropped outside of async code, the lock is taken on a
+        // task instead.
+        tokio::spawn(async move {
+            let mut roster = roster.lock().await;
+            match player_id {
+                Some(player_id) => roster.disconnect(&player_id),
+                None => roster.spectators -= 1,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn is_away(roster: &SharedRoster, player_id: &str) -> bool {
+        roster.lock().await.away().any(|p| p == player_id)
+    }
+
+    #[tokio::test]
+    async fn only_away_players_expire() {
+        let roster = Roster::shared(Duration::ZERO);
+        roster.lock().await.join("a");
+        roster.lock().await.join("b");
+
+        assert!(!roster.lock().await.any_connected());
+        let connection = Connection::open(&roster, "a").await.unwrap();
+        assert!(!is_away(&roster, "a").await);
+        assert!(roster.lock().await.any_connected());
+        assert_eq!(roster.lock().await.expire_away(), vec!["b".to_string()]);
+        assert!(Connection::open(&roster, "b").await.is_none());
+
+        drop(connection);
+        tokio::task::yield_now().await;
+        assert!(is_away(&roster, "a").await);
+        assert_eq!(roster.lock().await.expire_away(), vec!["a".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn players_resume_within_the_grace_period() {
+        let roster = Roster::shared(Duration::from_secs(60));
+        roster.lock().await.join("a");
+
+        drop(Connection::open(&roster, "a").await.unwrap());
+        tokio::task::yield_now().await;
+        assert!(roster.lock().await.expire_away().is_empty());
+
+        let _connection = Connection::open(&roster, "a").await.unwrap();
+        assert!(!is_away(&roster, "a").await);
+    }
+
+    #[tokio::test]
+    async fn spectators_are_counted_while_watching() {
+        let roster = Roster::shared(Duration::ZERO);
+
+        let spectator = Connection::spectate(&roster).await;
+        assert!(spectator.is_spectator());
+        assert_eq!(roster.lock().await.spectators(), 1);
+
+        drop(spectator);
+        tokio::task::yield_now().await;
+        assert_eq!(roster.lock().await.spectators(), 0);
+        assert!(roster.lock().await.expire_away().is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::updates::{ControlAssignment, Instruction};
+
+pub type SharedSplitControl = Arc<Mutex<SplitControl>>;
+
+// Instruction groups handed out to players, in the order they are dealt.
+const INSTRUCTION_GROUPS: [&[Instruction]; 4] = [
+    &[Instruction::Left, Instruction::Right],
+    &[Instruction::Up, Instruction::Down],
+    &[Instruction::Jump],
+    &[Instruction::Cw, Instruction::Ccw],
+];
+
+/// Ownership of instructions when every player controls a different part of
+/// the shared pawn. Players join on their first instruction and leave after
+/// being idle for longer than the timeout or when they leave the room.
+#[derive(Debug)]
+pub struct SplitControl {
+    idle_timeout: Duration,
+    players: Vec<String>,
+    last_seen: HashMap<String, Instant>,
+    owners: HashMap<Instruction, String>,
+    changed: bool,
+}
+
+impl SplitControl {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            players: vec![],
+            last_seen: HashMap::new(),
+            owners: HashMap::new(),
+            changed: false,
+        }
+    }
+
+    pub fn shared(idle_timeout: Duration) -> SharedSplitControl {
+        Arc::new(Mutex::new(Self::new(idle_timeout)))
+    }
+
+    /// Marks the player as active, joining them if they are new.
+    pub fn touch(&mut self, player_id: &str) {
+        if self
+            .last_seen
+            .insert(player_id.to_string(), Instant::now())
+            .is_none()
+        {
+            self.players.push(player_id.to_string());
+            self.rebalance();
+        }
+    }
+
+    /// Keeps an existing player's slot from idling out without joining anyone,
+    /// used while a disconnected player may still resume.
+    pub fn hold(&mut self, player_id: &str) {
+        if let Some(seen) = self.last_seen.get_mut(player_id) {
+            *seen = Instant::now();
+        }
+    }
+
+    pub fn remove(&mut self, player_id: &str) {
+        if self.last_seen.remove(player_id).is_some() {
+            self.players.retain(|p| p != player_id);
+            self.rebalance();
+        }
+    }
+
+    pub fn owns(&self, player_id: &str, instruction: Instruction) -> bool {
+        self.owners
+            .get(&instruction)
+            .is_some_and(|owner| owner == player_id)
+    }
+
+    /// Removes players that have been idle for longer than the timeout.
+    pub fn expire_idle(&mut self) {
+        let now = Instant::now();
+        let before = self.players.len();
+
+        self.last_seen
+            .retain(|_, seen| now.duration_since(*seen) < self.idle_timeout);
+        self.players.retain(|p| self.last_seen.contains_key(p));
+
+        if self.players.len() != before {
+            self.rebalance();
+        }
+    }
+
+    /// Returns the current assignments if they changed since the last call.
+    pub fn take_changes(&mut self) -> Option<Vec<ControlAssignment>> {
+        std::mem::take(&mut self.changed).then(|| self.assignments())
+    }
+
+    pub fn assignments(&self) -> Vec<ControlAssignment> {
+        self.players
+            .iter()
+            .map(|player_id| ControlAssignment {
+                player_id: player_id.clone(),
+                instructions: INSTRUCTION_GROUPS
+                    .iter()
+                    .flat_map(|group| group.iter())
+                    .filter(|ins| self.owns(player_id, **ins))
+                    .map(|ins| *ins as i32)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Deals the instruction groups out in contiguous chunks so that with two
+    /// players one moves and the other jumps and turns. Players beyond the
+    /// number of groups wait for a free slot.
+    fn rebalance(&mut self) {
+        self.owners.clear();
+        self.changed = true;
+
+        let count = self.players.len();
+        if count == 0 {
+            return;
+        }
+
+        for (i, group) in INSTRUCTION_GROUPS.iter().enumerate() {
+            let owner = &self.players[i * count / INSTRUCTION_GROUPS.len()];
+            for instruction in group.iter() {
+                self.owners.insert(*instruction, owner.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(split: &SplitControl) -> Vec<(String, usize)> {
+        split
+            .assignments()
+            .into_iter()
+            .map(|a| (a.player_id, a.instructions.len()))
+            .collect()
+    }
+
+    fn with_players(count: usize) -> SplitControl {
+        let mut split = SplitControl::new(Duration::from_secs(60));
+        for i in 0..count {
+            split.touch(&format!("p{i}"));
+        }
+        split
+    }
+
+    #[test]
+    fn groups_are_dealt_in_contiguous_chunks() {
+        let split = with_players(1);
+        assert_eq!(owned(&split), vec![("p0".into(), 7)]);
+
+        // One player moves, the other jumps and turns.
+        let split = with_players(2);
+        assert!(split.owns("p0", Instruction::Left) && split.owns("p0", Instruction::Up));
+        assert!(split.owns("p1", Instruction::Jump) && split.owns("p1", Instruction::Cw));
+
+        let split = with_players(3);
+        assert_eq!(
+            owned(&split),
+            vec![("p0".into(), 4), ("p1".into(), 1), ("p2".into(), 2)]
+        );
+
+        let split = with_players(4);
+        assert_eq!(
+            owned(&split),
+            vec![
+                ("p0".into(), 2),
+                ("p1".into(), 2),
+                ("p2".into(), 1),
+                ("p3".into(), 2)
+            ]
+        );
+
+        // Players beyond the number of groups wait for a free slot.
+        let split = with_players(5);
+        assert_eq!(owned(&split)[4], ("p4".into(), 0));
+        assert!(!split.owns("p1", Instruction::Left));
+    }
+
+    #[test]
+    fn changes_are_reported_once() {
+        let mut split = with_players(1);
+        assert_eq!(split.take_changes().map(|a| a.len()), Some(1));
+        assert!(split.take_changes().is_none());
+
+        // Activity from a known player changes nothing.
+        split.touch("p0");
+        assert!(split.take_changes().is_none());
+
+        split.touch("p1");
+        assert_eq!(split.take_changes().map(|a| a.len()), Some(2));
+
+        split.remove("p0");
+        assert!(split.owns("p1", Instruction::Left));
+        assert_eq!(split.take_changes().map(|a| a.len()), Some(1));
+    }
+
+    #[test]
+    fn idle_players_expire() {
+        let mut split = with_players(2);
+        split.expire_idle();
+        assert_eq!(split.assignments().len(), 2);
+
+        let mut split = SplitControl::new(Duration::ZERO);
+        split.touch("p0");
+        split.take_changes();
+
+        split.expire_idle();
+        assert!(split.assignments().is_empty());
+        assert!(!split.owns("p0", Instruction::Jump));
+        assert_eq!(split.take_changes().map(|a| a.len()), Some(0));
+
+        // Holding a slot never adds a player.
+        split.hold("p0");
+        assert!(split.assignments().is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+use tokio::time::{Duration, Instant};
+use tracing::warn;
+
+/// Turns real elapsed time into a whole number of fixed physics steps, so the
+/// simulation keeps pace with the wall clock however the loop gets scheduled.
+#[derive(Debug)]
+pub struct FixedTimestep {
+    dt: Duration,
+    max_substeps: u32,
+    accumulator: Duration,
+    last: Instant,
+    overruns: u64,
+}
+
+impl FixedTimestep {
+    pub fn new(dt: Duration, max_substeps: u32) -> Self {
+        Self {
+            dt,
+            max_substeps,
+            accumulator: Duration::ZERO,
+            last: Instant::now(),
+            overruns: 0,
+        }
+    }
+
+    /// Number of steps owed for the time passed since the last call. When more
+    /// than `max_substeps` are owed the backlog is dropped and counted as an
+    /// overrun, trading simulation time for staying responsive.
+    pub fn advance(&mut self) -> u32 {
+        let now = Instant::now();
+        self.accumulator += now - self.last;
+        self.last = now;
+
+        let owed = (self.accumulator.as_nanos() / self.dt.as_nanos()) as u32;
+
+        if owed > self.max_substeps {
+            self.overruns += 1;
+            warn!(
+                owed,
+                max_substeps = self.max_substeps,
+                overruns = self.overruns,
+                "Simulation tick overrun, dropping backlog"
+            );
+            self.accumulator = Duration::ZERO;
+            return self.max_substeps;
+        }
+
+        self.accumulator -= self.dt * owed;
+        owed
+    }
+
+    /// Forgets time passed since the last call, for resuming after the
+    /// simulation was paused.
+    pub fn reset(&mut self) {
+        self.accumulator = Duration::ZERO;
+        self.last = Instant::now();
+    }
+
+    pub fn dt(&self) -> Duration {
+        self.dt
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: Duration = Duration::from_millis(4);
+
+    #[tokio::test(start_paused = true)]
+    async fn leftover_time_carries_over() {
+        let mut timestep = FixedTimestep::new(DT, 8);
+
+        tokio::time::advance(Duration::from_millis(10)).await;
+        assert_eq!(timestep.advance(), 2);
+
+        // 2ms were left over, another 2ms complete a step.
+        tokio::time::advance(Duration::from_millis(2)).await;
+        assert_eq!(timestep.advance(), 1);
+        assert_eq!(timestep.advance(), 0);
+        assert_eq!(timestep.overruns(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backlog_beyond_the_cap_is_dropped() {
+        let mut timestep = FixedTimestep::new(DT, 8);
+
+        tokio::time::advance(DT * 20).await;
+        assert_eq!(timestep.advance(), 8);
+        assert_eq!(timestep.overruns(), 1);
+
+        // The rest of the backlog is gone rather than owed.
+        tokio::time::advance(DT).await;
+        assert_eq!(timestep.advance(), 1);
+        assert_eq!(timestep.overruns(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reset_forgets_paused_time() {
+        let mut timestep = FixedTimestep::new(DT, 8);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        timestep.reset();
+        tokio::time::advance(DT).await;
+        assert_eq!(timestep.advance(), 1);
+        assert_eq!(timestep.overruns(), 0);
+    }
+}
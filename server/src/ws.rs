@@ -1,138 +1,378 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use anyhow::{anyhow, Result};
-use futures_util::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use prost::Message as _;
 use tokio::{
     net::{TcpListener, TcpStream, ToSocketAddrs},
     select,
-    sync::{broadcast, mpsc, Mutex},
 };
-use tokio_tungstenite::tungstenite::Message;
-use tracing::warn;
-
-use crate::simulation::{Instruction, SimulationUpdate};
-
-type Tx = mpsc::UnboundedSender<Payload>;
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Payload {
-    // Send + Recv
-    ChatMessage(String),
-
-    // Send
-    SimulationUpdate(SimulationUpdate),
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tonic::{Request, Status};
+use tracing::{info, instrument, warn};
 
-    // Recv
-    SimulationInstruction(Instruction),
+use crate::{
+    service::simulation_service::SimulationUpdateService,
+    session::{unknown_session, Session, SessionStore},
+    updates::{
+        client_message, server_message, simulation_service_server::SimulationService,
+        ClientMessage, InstructionRejected, ServerError, ServerMessage, SimulationEvent,
+    },
+};
 
-    NoPayload
-}
+type UpdateStream = <SimulationUpdateService as SimulationService>::SubscribeToSimulationStream;
+type Outgoing = SplitSink<WebSocketStream<TcpStream>, Message>;
 
 enum Action {
-    Send(Payload),
-    Recv(Payload),
+    Send(ServerMessage),
+    Recv(client_message::Message),
+    Fail(Status),
     Noop,
     Close,
 }
 
-#[derive(Debug, Clone)]
-pub struct ServerContext {
-    pub sim_channel: broadcast::Sender<SimulationUpdate>,
-    pub instruction_channel: mpsc::Sender<Instruction>,
-}
-
-pub async fn run_server(ctx: ServerContext, addr: impl ToSocketAddrs) -> Result<()> {
-    let peer_map = PeerMap::new(Mutex::new(HashMap::new()));
+/// Accepts WebSocket clients, each speaking binary `ClientMessage` and
+/// `ServerMessage` frames. Subscriptions and instructions go through the same
+/// service as the gRPC transport.
+pub async fn run_server(
+    service: SimulationUpdateService,
+    sessions: Arc<SessionStore>,
+    addr: impl ToSocketAddrs,
+) -> Result<()> {
     let listener = TcpListener::bind(&addr).await?;
+    info!(addr = %listener.local_addr()?, "Starting WebSocket server");
 
     while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(client_handler(ctx.clone(), peer_map.clone(), stream, addr));
+        let service = service.clone();
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client_handler(service, sessions, stream, addr).await {
+                warn!(%addr, err=%e, "WebSocket client failed");
+            }
+        });
     }
 
     Ok(())
 }
 
+#[instrument(skip(service, sessions, stream))]
 pub async fn client_handler(
-    ctx: ServerContext,
-    peer_map: PeerMap,
+    service: SimulationUpdateService,
+    sessions: Arc<SessionStore>,
     stream: TcpStream,
     addr: SocketAddr,
 ) -> Result<()> {
     let ws_stream = tokio_tungstenite::accept_async(stream).await?;
-    let (ws_tx, mut ws_rx) = mpsc::unbounded_channel();
-    let mut sim_update_rx = ctx.sim_channel.subscribe();
-    let ins_tx = ctx.instruction_channel;
-
-    peer_map.lock()
-        .await
-        .insert(addr, ws_tx.clone());
-
     let (mut outgoing, mut incoming) = ws_stream.split();
+    let mut updates: Option<UpdateStream> = None;
+    let mut session: Option<Session> = None;
 
     loop {
-        let res: Result<Action> = select! {
-            sim_up = sim_update_rx.recv() => {
-                sim_up
-                    .map(|up| Action::Send(Payload::SimulationUpdate(up)))
-                    .map_err(|e| anyhow!("{e:?}"))
+        let action = select! {
+            update = next_update(&mut updates) => match update {
+                Some(Ok(event)) => Action::Send(ServerMessage {
+                    message: Some(server_message::Message::Event(event)),
+                }),
+                Some(Err(status)) => {
+                    warn!(%status, "Simulation stream failed");
+                    updates = None;
+                    Action::Fail(status)
+                }
+                None => Action::Close,
             },
-            recvd = incoming.next() => {
-                let payload = match recvd {
-                    Some(Ok(Message::Text(s))) => {
-                        match s.chars().next() {
-                            Some('I') => {
-                                let target = s.chars().skip_while(|c| c != &'{').collect::<String>();
-                                let deser = serde_json::from_str::<Instruction>(&target);
-                                deser      
-                                    .map(|ins| Payload::SimulationInstruction(ins))
-                                    .map_err(|e| {
-                                        warn!(err=?e, "could not parse {s}");
-                                        anyhow!("{e:?}")
-                                    })
-                                    .ok()
-                            },
-                            _ => Some(Payload::NoPayload),
-                        }
-                    },
-                    v => {
-                        warn!("Unknown {v:?}");
-                        None
-                    },
-                };
-
-                match payload {
-                    Some(payload) if matches!(payload, Payload::SimulationInstruction(_)) => Ok(Action::Recv(payload)),
-                    Some(payload) if matches!(payload, Payload::ChatMessage(_)) => Ok(Action::Recv(payload)),
-                    Some(Payload::NoPayload) => Ok(Action::Noop),
-                    None => Ok(Action::Close),
-                    _ => Err(anyhow!("Unknown"))
+            recvd = incoming.next() => match recvd {
+                Some(Ok(Message::Binary(bytes))) => match ClientMessage::decode(bytes.as_slice()) {
+                    Ok(ClientMessage { message: Some(message) }) => Action::Recv(message),
+                    Ok(ClientMessage { message: None }) => Action::Noop,
+                    Err(e) => Action::Fail(Status::invalid_argument(format!(
+                        "Malformed client message: {e}"
+                    ))),
+                },
+                Some(Ok(Message::Close(_))) | None => Action::Close,
+                Some(Ok(Message::Text(_))) => {
+                    warn!("Ignoring text frame, only binary protobuf frames are supported");
+                    Action::Noop
                 }
+                // Pings are answered by tungstenite itself.
+                Some(Ok(_)) => Action::Noop,
+                Some(Err(e)) => return Err(e.into()),
             },
-            else => { break }
         };
-    
-        if res.is_err() {
-            return res.map(|_| ());
-        }
 
-        match res.unwrap() {
-            Action::Send(payload) if matches!(payload, Payload::SimulationUpdate(_)) => {
-                let ser = serde_json::to_string(&payload)?;
-                outgoing.send(Message::Text(ser)).await?;
-            },
-            Action::Recv(Payload::ChatMessage(_s)) => {
-                //incoming here
-                unimplemented!()
-            },
-            Action::Recv(Payload::SimulationInstruction(instruction)) => {
-                ins_tx.send(instruction).await?;
-            },
+        match action {
+            Action::Send(message) => {
+                outgoing
+                    .send(Message::Binary(message.encode_to_vec()))
+                    .await?;
+            }
+            Action::Recv(client_message::Message::Subscribe(request)) => {
+                if updates.is_some() {
+                    warn!("Ignoring repeated subscription");
+                    continue;
+                }
+
+                match service
+                    .subscribe_to_simulation(with_session(request, &session))
+                    .await
+                {
+                    Ok(stream) => updates = Some(stream.into_inner()),
+                    Err(status) => send_error(&mut outgoing, status).await?,
+                }
+            }
+            Action::Recv(client_message::Message::Instruction(instruction)) => {
+                let sequence = instruction.sequence;
+
+                if let Err(status) = service
+                    .send_instruction(with_session(instruction, &session))
+                    .await
+                {
+                    let rejected = ServerMessage {
+                        message: Some(server_message::Message::Rejected(InstructionRejected {
+                            sequence,
+                            reason: status.message().to_string(),
+                        })),
+                    };
+                    outgoing
+                        .send(Message::Binary(rejected.encode_to_vec()))
+                        .await?;
+                }
+            }
+            Action::Recv(client_message::Message::SessionToken(token)) => {
+                match sessions.get(&token) {
+                    Some(found) => session = Some(found),
+                    None => send_error(&mut outgoing, unknown_session()).await?,
+                }
+            }
+            Action::Fail(status) => send_error(&mut outgoing, status).await?,
             Action::Noop => continue,
             Action::Close => break,
-            _ => return Err(anyhow!("Invalid action")),
         }
     }
 
+    info!("WebSocket client disconnected");
+    Ok(())
+}
+
+/// Tells the client why a message failed without closing the connection.
+async fn send_error(outgoing: &mut Outgoing, status: Status) -> Result<()> {
+    warn!(%status, "Rejecting WebSocket client message");
+    let error = ServerMessage {
+        message: Some(server_message::Message::Error(ServerError {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+        })),
+    };
+
+    outgoing
+        .send(Message::Binary(error.encode_to_vec()))
+        .await?;
     Ok(())
 }
+
+/// Wraps a message the way the session interceptor would for gRPC calls.
+fn with_session<T>(message: T, session: &Option<Session>) -> Request<T> {
+    let mut request = Request::new(message);
+    if let Some(session) = session {
+        request.extensions_mut().insert(session.clone());
+    }
+    request
+}
+
+/// Next update of the subscription, never resolving before the client has
+/// subscribed.
+async fn next_update(
+    updates: &mut Option<UpdateStream>,
+) -> Option<Result<SimulationEvent, Status>> {
+    match updates {
+        Some(updates) => updates.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        room::{self, RoomRegistry, DEFAULT_ROOM_ID},
+        updates::{
+            instruction_update::Input, simulation_event::Event, InputState, Instruction,
+            InstructionUpdate, SubscriptionRequest,
+        },
+    };
+    use tokio::time::{timeout, Duration};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Starts a registry and a WebSocket listener for a single client.
+    async fn connect() -> (Arc<RoomRegistry>, Arc<SessionStore>, Client) {
+        let rooms = Arc::new(RoomRegistry::new(room::tests::config()).unwrap());
+        let sessions = Arc::new(SessionStore::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let service = SimulationUpdateService::new(rooms.clone());
+        let handler_sessions = sessions.clone();
+        tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            client_handler(service, handler_sessions, stream, addr)
+                .await
+                .unwrap();
+        });
+
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+        (rooms, sessions, client)
+    }
+
+    async fn send(client: &mut Client, message: client_message::Message) {
+        let frame = ClientMessage {
+            message: Some(message),
+        };
+        client
+            .send(Message::Binary(frame.encode_to_vec()))
+            .await
+            .unwrap();
+    }
+
+    async fn recv(client: &mut Client) -> server_message::Message {
+        let frame = timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("no message from the server")
+            .unwrap()
+            .unwrap();
+        ServerMessage::decode(frame.into_data().as_slice())
+            .unwrap()
+            .message
+            .unwrap()
+    }
+
+    fn press_right(sequence: u32) -> InstructionUpdate {
+        InstructionUpdate {
+            input: Some(Input::Instruction(Instruction::Right as i32)),
+            state: InputState::Press as i32,
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let messages = [
+            client_message::Message::Subscribe(SubscriptionRequest {
+                room_id: "room-1".into(),
+                ..Default::default()
+            }),
+            client_message::Message::Instruction(press_right(7)),
+            client_message::Message::SessionToken("token".into()),
+        ];
+        for message in messages {
+            let frame = ClientMessage {
+                message: Some(message),
+            };
+            let decoded = ClientMessage::decode(frame.encode_to_vec().as_slice()).unwrap();
+            assert_eq!(decoded, frame);
+        }
+
+        let frame = ServerMessage {
+            message: Some(server_message::Message::Error(ServerError {
+                code: tonic::Code::Unauthenticated as i32,
+                message: "nope".into(),
+            })),
+        };
+        let decoded = ServerMessage::decode(frame.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, frame);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_session_are_rejected() {
+        let rooms = Arc::new(RoomRegistry::new(room::tests::config()).unwrap());
+        let service = SimulationUpdateService::new(rooms);
+
+        let status = service
+            .send_instruction(with_session(press_right(1), &None))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let spectator = Session {
+            player_id: "player-1".into(),
+            room_id: DEFAULT_ROOM_ID.into(),
+            spectator: true,
+        };
+        let status = service
+            .send_instruction(with_session(press_right(1), &Some(spectator)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn unknown_tokens_get_an_error_frame() {
+        let (_rooms, _sessions, mut client) = connect().await;
+
+        send(
+            &mut client,
+            client_message::Message::SessionToken("nope".into()),
+        )
+        .await;
+        let server_message::Message::Error(error) = recv(&mut client).await else {
+            panic!("expected an error frame");
+        };
+        assert_eq!(error.code, tonic::Code::Unauthenticated as i32);
+
+        // The connection stays usable, unauthenticated instructions are
+        // rejected by sequence.
+        send(
+            &mut client,
+            client_message::Message::Instruction(press_right(3)),
+        )
+        .await;
+        let server_message::Message::Rejected(rejected) = recv(&mut client).await else {
+            panic!("expected a rejection");
+        };
+        assert_eq!(rejected.sequence, 3);
+    }
+
+    #[tokio::test]
+    async fn instructions_are_acked_on_the_stream() {
+        let (rooms, sessions, mut client) = connect().await;
+        let room = rooms.find(DEFAULT_ROOM_ID).await.unwrap();
+        let joined = room.join(&sessions, false).await.unwrap();
+
+        send(
+            &mut client,
+            client_message::Message::SessionToken(joined.session_token),
+        )
+        .await;
+        send(
+            &mut client,
+            client_message::Message::Subscribe(SubscriptionRequest {
+                include_events: true,
+                ..Default::default()
+            }),
+        )
+        .await;
+        send(
+            &mut client,
+            client_message::Message::Instruction(press_right(5)),
+        )
+        .await;
+
+        loop {
+            let server_message::Message::Event(event) = recv(&mut client).await else {
+                panic!("the instruction should be accepted");
+            };
+            if let Some(Event::Snapshot(update)) = event.event {
+                if update
+                    .acks
+                    .iter()
+                    .any(|ack| ack.player_id == joined.player_id && ack.sequence == 5)
+                {
+                    break;
+                }
+            }
+        }
+    }
+}